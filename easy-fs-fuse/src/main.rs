@@ -2,13 +2,22 @@ use easy_fs::{
     BlockDevice,
     EasyFileSystem,
 };
+#[cfg(test)]
+use easy_fs::{
+    Error,
+    Fault,
+    RamBlockDevice,
+};
 use std::fs::{File, OpenOptions, read_dir};
 use std::io::{Read, Write, Seek, SeekFrom};
 use std::sync::Mutex;
 use std::sync::Arc;
-use clap::{Arg, App};
+use std::time::{SystemTime, UNIX_EPOCH};
+use clap::{Arg, App, ArgMatches, SubCommand};
 
 const BLOCK_SZ: usize = 512;
+/// Blocks kept in the block cache of easy-fs.
+const BLOCK_CACHE_SIZE: usize = 1024;
 
 struct BlockFile(Mutex<File>);
 
@@ -26,13 +35,32 @@ impl BlockDevice for BlockFile {
             .expect("Error when seeking!");
         assert_eq!(file.write(buf).unwrap(), BLOCK_SZ, "Not a complete block!");
     }
+
+    // a whole file system block in one go rather than sector by sector
+    fn read_fs_block(&self, block_id: usize, buf: &mut [u8]) {
+        let mut file = self.0.lock().unwrap();
+        file.seek(SeekFrom::Start((block_id * buf.len()) as u64))
+            .expect("Error when seeking!");
+        file.read_exact(buf).expect("Not a complete block!");
+    }
+
+    fn write_fs_block(&self, block_id: usize, buf: &[u8]) {
+        let mut file = self.0.lock().unwrap();
+        file.seek(SeekFrom::Start((block_id * buf.len()) as u64))
+            .expect("Error when seeking!");
+        file.write_all(buf).expect("Not a complete block!");
+    }
 }
 
-fn main() {
-    easy_fs_pack().expect("Error when packing easy-fs!");
+/// Milliseconds since the Unix epoch.
+fn host_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
 }
 
-fn easy_fs_pack() -> std::io::Result<()> {
+fn main() {
     let matches = App::new("EasyFileSystem packer")
         .arg(Arg::with_name("source")
             .short("s")
@@ -46,9 +74,78 @@ fn easy_fs_pack() -> std::io::Result<()> {
             .takes_value(true)
             .help("Executable target dir(with backslash)")    
         )
+        .arg(Arg::with_name("block-size")
+            .short("b")
+            .long("block-size")
+            .takes_value(true)
+            .possible_values(&["512", "1024", "4096"])
+            .default_value("512")
+            .help("Block size of the image in bytes")
+        )
+        .arg(Arg::with_name("xattr")
+            .short("x")
+            .long("xattr")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Tag an app with an extended attribute, given as app:name=value")
+        )
+        .arg(Arg::with_name("compress")
+            .short("z")
+            .long("compress")
+            .help("Compress the apps, so that more of them fit the image")
+        )
+        .subcommand(SubCommand::with_name("fsck")
+            .about("Check the consistency of an easy-fs image")
+            .arg(Arg::with_name("image")
+                .required(true)
+                .help("Path of the image")
+            )
+            .arg(Arg::with_name("repair")
+                .long("repair")
+                .help("Give leaked inodes and blocks back to the bitmaps and fix the free counts")
+            )
+        )
         .get_matches();
+    if let Some(matches) = matches.subcommand_matches("fsck") {
+        if !easy_fs_fsck(matches).expect("Error when checking easy-fs!") {
+            std::process::exit(1);
+        }
+    } else {
+        easy_fs_pack(&matches).expect("Error when packing easy-fs!");
+    }
+}
+
+/// Return whether the image was found consistent.
+fn easy_fs_fsck(matches: &ArgMatches) -> std::io::Result<bool> {
+    let image_path = matches.value_of("image").unwrap();
+    let repair = matches.is_present("repair");
+    let block_file = Arc::new(BlockFile(Mutex::new(
+        OpenOptions::new()
+            .read(true)
+            .write(repair)
+            .open(image_path)?
+    )));
+    let efs = EasyFileSystem::open(block_file, host_time, BLOCK_CACHE_SIZE).map_err(|err| {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{:?}", err))
+    })?;
+    let problems = EasyFileSystem::fsck(&efs, repair);
+    for problem in problems.iter() {
+        println!("{:?}", problem);
+    }
+    if problems.is_empty() {
+        println!("{}: clean", image_path);
+    } else {
+        println!("{}: {} problem(s) found", image_path, problems.len());
+    }
+    Ok(problems.is_empty())
+}
+
+fn easy_fs_pack(matches: &ArgMatches) -> std::io::Result<()> {
     let src_path = matches.value_of("source").unwrap();
     let target_path = matches.value_of("target").unwrap();
+    let block_size: usize = matches.value_of("block-size").unwrap().parse().unwrap();
+    let compress = matches.is_present("compress");
     println!("src_path = {}\ntarget_path = {}", src_path, target_path);
     let block_file = Arc::new(BlockFile(Mutex::new({
         let f = OpenOptions::new()
@@ -56,19 +153,20 @@ fn easy_fs_pack() -> std::io::Result<()> {
             .write(true)
             .create(true)
             .open(format!("{}{}", target_path, "fs.img"))?;
-        f.set_len(8192 * 512).unwrap();
+        f.set_len((8192 * block_size) as u64).unwrap();
         f
     })));
-    // 4MiB, at most 4095 files
+    // 4MiB with 512-byte blocks, at most 4095 files
     let efs = EasyFileSystem::create(
         block_file.clone(),
         8192,
         1,
-    );
+        block_size,
+        host_time,
+    ).map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{:?}", err)))?;
     let root_inode = Arc::new(EasyFileSystem::root_inode(&efs));
     let apps: Vec<_> = read_dir(src_path)
         .unwrap()
-        .into_iter()
         .map(|dir_entry| {
             let mut name_with_ext = dir_entry.unwrap().file_name().into_string().unwrap();
             name_with_ext.drain(name_with_ext.find('.').unwrap()..name_with_ext.len());
@@ -82,16 +180,42 @@ fn easy_fs_pack() -> std::io::Result<()> {
         host_file.read_to_end(&mut all_data).unwrap();
         // create a file in easy-fs
         let inode = root_inode.create(app.as_str()).unwrap();
+        if compress {
+            inode.set_compressed().unwrap();
+        }
         // write data to easy-fs
-        inode.write_at(0, all_data.as_slice());
+        inode.write_at(0, all_data.as_slice()).unwrap();
+        // apps must be executable
+        inode.set_mode(0o755).unwrap();
+    }
+    for xattr in matches.values_of("xattr").into_iter().flatten() {
+        let invalid = || {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("bad xattr {}", xattr))
+        };
+        let colon = xattr.find(':').ok_or_else(invalid)?;
+        let equals = colon + xattr[colon..].find('=').ok_or_else(invalid)?;
+        let (app, name, value) = (&xattr[..colon], &xattr[colon + 1..equals], &xattr[equals + 1..]);
+        root_inode
+            .find(app)
+            .and_then(|inode| inode.set_xattr(name, value.as_bytes()))
+            .map_err(|err| {
+                std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{}: {:?}", xattr, err))
+            })?;
     }
     // list apps
-    for app in root_inode.ls() {
+    for app in root_inode.ls().unwrap() {
         println!("{}", app);
     }
     Ok(())
 }
 
+/// Image of 8192 blocks in memory, 4MiB with 512-byte blocks. Each test
+/// takes its own, so that they neither share state nor wait on each other.
+#[cfg(test)]
+fn ram_image() -> Arc<RamBlockDevice> {
+    Arc::new(RamBlockDevice::new(8192))
+}
+
 #[test]
 fn efs_test() -> std::io::Result<()> {
     let block_file = Arc::new(BlockFile(Mutex::new({
@@ -99,51 +223,54 @@ fn efs_test() -> std::io::Result<()> {
             .read(true)
             .write(true)
             .create(true)
+            .truncate(true)
             .open("target/fs.img")?;
         f.set_len(8192 * 512).unwrap();
         f
     })));
     EasyFileSystem::create(
         block_file.clone(),
-        4096,
+        8192,
         1,
-    );
-    let efs = EasyFileSystem::open(block_file.clone());
+        BLOCK_SZ,
+        host_time,
+    ).unwrap();
+    let efs = EasyFileSystem::open(block_file.clone(), host_time, BLOCK_CACHE_SIZE).unwrap();
     let root_inode = EasyFileSystem::root_inode(&efs);
-    root_inode.create("filea");
-    root_inode.create("fileb");
-    for name in root_inode.ls() {
+    root_inode.create("filea").unwrap();
+    root_inode.create("fileb").unwrap();
+    for name in root_inode.ls().unwrap() {
         println!("{}", name);
     }
     let filea = root_inode.find("filea").unwrap();
     let greet_str = "Hello, world!";
-    filea.write_at(0, greet_str.as_bytes());
+    filea.write_at(0, greet_str.as_bytes()).unwrap();
     //let mut buffer = [0u8; 512];
     let mut buffer = [0u8; 233];
-    let len = filea.read_at(0, &mut buffer);
+    let len = filea.read_at(0, &mut buffer).unwrap();
     assert_eq!(
         greet_str,
         core::str::from_utf8(&buffer[..len]).unwrap(),
     );
 
     let mut random_str_test = |len: usize| {
-        filea.clear();
+        filea.clear().unwrap();
         assert_eq!(
-            filea.read_at(0, &mut buffer),
+            filea.read_at(0, &mut buffer).unwrap(),
             0,
         );
         let mut str = String::new();
         use rand;
         // random digit
         for _ in 0..len {
-            str.push(char::from('0' as u8 + rand::random::<u8>() % 10));
+            str.push(char::from(b'0' + rand::random::<u8>() % 10));
         }
-        filea.write_at(0, str.as_bytes());
+        filea.write_at(0, str.as_bytes()).unwrap();
         let mut read_buffer = [0u8; 127];
         let mut offset = 0usize;
         let mut read_str = String::new();
         loop {
-            let len = filea.read_at(offset, &mut read_buffer);
+            let len = filea.read_at(offset, &mut read_buffer).unwrap();
             if len == 0 {
                 break;
             }
@@ -165,4 +292,1002 @@ fn efs_test() -> std::io::Result<()> {
     random_str_test(2000 * BLOCK_SZ);
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[test]
+fn timestamps() {
+    let efs = EasyFileSystem::create(ram_image(), 8192, 1, BLOCK_SZ, host_time).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let filea = root_inode.create("filea").unwrap();
+    let created = filea.stat().unwrap().ctime;
+    assert!(created > 0);
+    filea.write_at(0, "Hello, world!".as_bytes()).unwrap();
+    assert!(filea.stat().unwrap().mtime >= created);
+    let mut buffer = [0u8; 233];
+    filea.read_at(0, &mut buffer).unwrap();
+    let stat = filea.stat().unwrap();
+    assert!(stat.atime >= stat.mtime);
+    assert_eq!(stat.ctime, created);
+}
+
+#[test]
+fn mode_and_owner() {
+    let efs = EasyFileSystem::create(ram_image(), 8192, 1, BLOCK_SZ, host_time).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let filea = root_inode.create("filea").unwrap();
+    assert_eq!(filea.stat().unwrap().mode, 0o644);
+    assert_eq!(root_inode.stat().unwrap().mode, 0o755);
+    // bits past the permission bits are dropped
+    filea.set_mode(0o4755).unwrap();
+    filea.set_owner(1000, 100).unwrap();
+    let stat = filea.stat().unwrap();
+    assert_eq!((stat.mode, stat.uid, stat.gid), (0o755, 1000, 100));
+}
+
+#[test]
+fn directories() {
+    let efs = EasyFileSystem::create(ram_image(), 8192, 1, BLOCK_SZ, host_time).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let greet_str = "Hello, world!";
+    let mut buffer = [0u8; 233];
+    let bin = root_inode.create_dir("bin").unwrap();
+    assert!(bin.is_dir());
+    assert_eq!(root_inode.create_dir("bin").err(), Some(Error::Exists));
+    assert_eq!(bin.ls().unwrap(), vec![".", ".."]);
+    let sub = bin.create_dir("sub").unwrap();
+    // truncating a directory would drop its dirents
+    assert_eq!(bin.clear(), Err(Error::NotFile));
+    assert_eq!(bin.find("sub").unwrap().inode_id(), sub.inode_id());
+    let filec = sub.create("filec").unwrap();
+    filec.write_at(0, greet_str.as_bytes()).unwrap();
+    let found = root_inode.find_path("/bin/sub/filec").unwrap();
+    assert_eq!(found.inode_id(), filec.inode_id());
+    let len = found.read_at(0, &mut buffer).unwrap();
+    assert_eq!(greet_str, core::str::from_utf8(&buffer[..len]).unwrap());
+    assert_eq!(sub.find_path("filec").unwrap().inode_id(), filec.inode_id());
+    assert_eq!(sub.find_path("../..").unwrap().inode_id(), root_inode.inode_id());
+    assert_eq!(sub.find_path("/").unwrap().inode_id(), root_inode.inode_id());
+    assert!(root_inode.find_path("bin/sub/filec/x").is_none());
+    assert!(root_inode.find_path("bin/none").is_none());
+    assert_eq!(EasyFileSystem::fsck(&efs, false), vec![]);
+}
+
+#[test]
+fn unlink_and_rmdir() {
+    let efs = EasyFileSystem::create(ram_image(), 8192, 1, BLOCK_SZ, host_time).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let before = efs.lock().stat_fs();
+    let bin = root_inode.create_dir("bin").unwrap();
+    let sub = bin.create_dir("sub").unwrap();
+    sub.create("filec").unwrap().write_at(0, b"Hello, world!").unwrap();
+    assert_eq!(root_inode.rmdir("bin"), Err(Error::NotEmpty));
+    assert_eq!(sub.unlink("."), Err(Error::InvalidName));
+    assert_eq!(bin.unlink("sub"), Err(Error::IsDir));
+    assert_eq!(bin.rmdir("none"), Err(Error::NotFound));
+    sub.unlink("filec").unwrap();
+    assert_eq!(sub.find("filec").err(), Some(Error::NotFound));
+    bin.rmdir("sub").unwrap();
+    assert_eq!(bin.ls().unwrap(), vec![".", ".."]);
+    root_inode.rmdir("bin").unwrap();
+    assert_eq!(root_inode.find("bin").err(), Some(Error::NotFound));
+    // inodes and blocks are reclaimed, so this would run out of both otherwise
+    for _ in 0..5000 {
+        let file = root_inode.create("tmp").unwrap();
+        file.write_at(0, &[0u8; 2 * BLOCK_SZ]).unwrap();
+        root_inode.unlink("tmp").unwrap();
+    }
+    assert_eq!(efs.lock().stat_fs(), before);
+    assert_eq!(EasyFileSystem::fsck(&efs, false), vec![]);
+}
+
+#[test]
+fn hard_links() {
+    let efs = EasyFileSystem::create(ram_image(), 8192, 1, BLOCK_SZ, host_time).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let greet_str = "Hello, world!";
+    let mut buffer = [0u8; 233];
+    let busybox = root_inode.create("busybox").unwrap();
+    busybox.write_at(0, greet_str.as_bytes()).unwrap();
+    root_inode.link("ls", &busybox).unwrap();
+    assert_eq!(root_inode.link("ls", &busybox), Err(Error::Exists));
+    assert_eq!(root_inode.link("root", &root_inode), Err(Error::IsDir));
+    assert_eq!(busybox.link("ls", &busybox), Err(Error::NotDir));
+    assert_eq!(busybox.stat().unwrap().nlink, 2);
+    root_inode.unlink("busybox").unwrap();
+    let ls = root_inode.find("ls").unwrap();
+    assert_eq!(ls.inode_id(), busybox.inode_id());
+    let len = ls.read_at(0, &mut buffer).unwrap();
+    assert_eq!(greet_str, core::str::from_utf8(&buffer[..len]).unwrap());
+    root_inode.unlink("ls").unwrap();
+    assert_eq!(EasyFileSystem::fsck(&efs, false), vec![]);
+}
+
+#[test]
+fn link_count_limit() {
+    // a link count that cannot go up any more fails the link rather than
+    // wrapping to 0, which would free the inode on the next unlink
+    let ram = ram_image();
+    let (block_id, offset) = {
+        let efs = EasyFileSystem::create(ram.clone(), 8192, 1, BLOCK_SZ, host_time).unwrap();
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        let busybox = root_inode.create("busybox").unwrap();
+        let pos = efs.lock().get_disk_inode_pos(busybox.inode_id());
+        pos
+    };
+    // set nlink, right after the type, on an image without checksums
+    let mut image = ram.image();
+    image[28..32].copy_from_slice(&6u32.to_le_bytes());
+    let nlink = block_id as usize * BLOCK_SZ + offset + 126;
+    image[nlink..nlink + 2].copy_from_slice(&(u16::MAX - 1).to_le_bytes());
+    let ram: Arc<dyn BlockDevice> = Arc::new(RamBlockDevice::from_image(image));
+    let efs = EasyFileSystem::open(ram, host_time, BLOCK_CACHE_SIZE).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let busybox = root_inode.find("busybox").unwrap();
+    root_inode.link("sh", &busybox).unwrap();
+    assert_eq!(busybox.stat().unwrap().nlink, u16::MAX);
+    assert_eq!(root_inode.link("ash", &busybox), Err(Error::TooManyLinks));
+    assert_eq!(root_inode.find("ash").err(), Some(Error::NotFound));
+    root_inode.unlink("sh").unwrap();
+    assert_eq!(busybox.stat().unwrap().nlink, u16::MAX - 1);
+}
+
+#[test]
+fn symlinks() {
+    let efs = EasyFileSystem::create(ram_image(), 8192, 1, BLOCK_SZ, host_time).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let filea = root_inode.create("filea").unwrap();
+    root_inode.create("fileb").unwrap();
+    let link = root_inode.symlink("greet", "/filea").unwrap();
+    assert_eq!(link.readlink().unwrap(), "/filea");
+    assert!(root_inode.readlink().is_none());
+    assert!(filea.readlink().is_none());
+    assert_eq!(root_inode.symlink("greet", "fileb").err(), Some(Error::Exists));
+    assert_eq!(root_inode.symlink("empty", "").err(), Some(Error::InvalidName));
+    // a symbolic link holds its target, not data of its own
+    assert_eq!(link.clear(), Err(Error::NotFile));
+    assert_eq!(link.readlink().unwrap(), "/filea");
+    root_inode.unlink("greet").unwrap();
+    assert_eq!(EasyFileSystem::fsck(&efs, false), vec![]);
+}
+
+#[test]
+fn rename() {
+    let efs = EasyFileSystem::create(ram_image(), 8192, 1, BLOCK_SZ, host_time).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let etc = root_inode.create_dir("etc").unwrap();
+    let tmp = root_inode.create_dir("tmp").unwrap();
+    let conf = etc.create("conf").unwrap();
+    let new_conf = tmp.create("conf.new").unwrap();
+    new_conf.write_at(0, b"Hello, world!").unwrap();
+    tmp.rename("conf.new", &etc, "conf").unwrap();
+    assert_eq!(tmp.find("conf.new").err(), Some(Error::NotFound));
+    assert_eq!(etc.find("conf").unwrap().inode_id(), new_conf.inode_id());
+    assert_ne!(conf.inode_id(), new_conf.inode_id());
+    etc.rename("conf", &etc, "conf.old").unwrap();
+    assert_eq!(etc.ls().unwrap(), vec![".", "..", "conf.old"]);
+    assert_eq!(root_inode.rename("etc", &etc, "etc"), Err(Error::InvalidName));
+    let nested = etc.create_dir("nested").unwrap();
+    assert_eq!(root_inode.rename("etc", &nested, "etc"), Err(Error::InvalidName));
+    assert_eq!(etc.rename("conf.old", &new_conf, "conf"), Err(Error::NotDir));
+    etc.rename("nested", &tmp, "nested").unwrap();
+    assert_eq!(nested.find("..").unwrap().inode_id(), tmp.inode_id());
+    assert_eq!(root_inode.rename("tmp", &root_inode, "etc"), Err(Error::NotEmpty));
+    tmp.rmdir("nested").unwrap();
+    etc.unlink("conf.old").unwrap();
+    root_inode.rename("tmp", &root_inode, "etc").unwrap();
+    assert_eq!(root_inode.find("tmp").err(), Some(Error::NotFound));
+    root_inode.rmdir("etc").unwrap();
+    assert_eq!(EasyFileSystem::fsck(&efs, false), vec![]);
+}
+
+#[test]
+fn failed_rename() {
+    // a rename failing after its new dirent is written is aborted as a
+    // whole, the target keeps its name
+    let ram = ram_image();
+    let (block_id, offset, target_id) = {
+        let efs = EasyFileSystem::create(ram.clone(), 8192, 1, BLOCK_SZ, host_time).unwrap();
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        root_inode.create("source").unwrap();
+        let target_id = root_inode.create("target").unwrap().inode_id();
+        let (block_id, offset) = efs.lock().get_disk_inode_pos(target_id);
+        (block_id, offset, target_id)
+    };
+    // a link count of 0 on an image without checksums
+    let mut image = ram.image();
+    image[28..32].copy_from_slice(&6u32.to_le_bytes());
+    let nlink = block_id as usize * BLOCK_SZ + offset + 126;
+    image[nlink..nlink + 2].copy_from_slice(&0u16.to_le_bytes());
+    let ram = Arc::new(RamBlockDevice::from_image(image));
+    let efs = EasyFileSystem::open(ram.clone(), host_time, BLOCK_CACHE_SIZE).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let free = efs.lock().stat_fs();
+    assert_eq!(root_inode.rename("source", &root_inode, "target"), Err(Error::Corrupt));
+    assert_eq!(root_inode.find("target").unwrap().inode_id(), target_id);
+    assert!(root_inode.find("source").is_ok());
+    assert_eq!(efs.lock().stat_fs(), free);
+    efs.lock().sync_all();
+    let ram: Arc<dyn BlockDevice> = Arc::new(RamBlockDevice::from_image(ram.image()));
+    let efs = EasyFileSystem::open(ram, host_time, BLOCK_CACHE_SIZE).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    assert_eq!(root_inode.find("target").unwrap().inode_id(), target_id);
+    assert!(root_inode.find("source").is_ok());
+}
+
+#[test]
+fn long_names() {
+    let efs = EasyFileSystem::create(ram_image(), 8192, 1, BLOCK_SZ, host_time).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let long_name = "x".repeat(255);
+    let too_long_name = "x".repeat(256);
+    let long = root_inode.create(long_name.as_str()).unwrap();
+    assert_eq!(root_inode.create(too_long_name.as_str()).err(), Some(Error::NameTooLong));
+    assert_eq!(root_inode.create("a/b").err(), Some(Error::InvalidName));
+    assert_eq!(root_inode.find(long_name.as_str()).unwrap().inode_id(), long.inode_id());
+    assert_eq!(root_inode.find(&long_name[..254]).err(), Some(Error::NotFound));
+    assert!(root_inode.ls().unwrap().contains(&long_name));
+    let names_before = root_inode.ls().unwrap();
+    root_inode.unlink(long_name.as_str()).unwrap();
+    // the freed slots can be taken by several short names
+    for i in 0..9 {
+        root_inode.create(format!("short{}", i).as_str()).unwrap();
+    }
+    for i in 0..9 {
+        root_inode.unlink(format!("short{}", i).as_str()).unwrap();
+    }
+    assert_eq!(root_inode.ls().unwrap().len(), names_before.len() - 1);
+    assert_eq!(EasyFileSystem::fsck(&efs, false), vec![]);
+}
+
+#[test]
+fn journal_commits() {
+    let ram = ram_image();
+    let efs = EasyFileSystem::create(ram.clone(), 8192, 1, BLOCK_SZ, host_time).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    // every operation commits, so data is on disk without waiting for
+    // eviction and the journal header is left with no pending blocks
+    let marker = "journaled, not only cached";
+    let file = root_inode.create("marker").unwrap();
+    file.write_at(0, marker.as_bytes()).unwrap();
+    let image = ram.image();
+    assert!(image.windows(marker.len()).any(|window| window == marker.as_bytes()));
+    assert_eq!(image[BLOCK_SZ + 4..BLOCK_SZ + 8], [0u8; 4]);
+    // a transaction larger than the journal is refused and aborted,
+    // allocated blocks are zeroed and so dirty
+    let free_blocks = {
+        let mut fs = efs.lock();
+        let free_blocks = fs.stat_fs().free_blocks;
+        assert_eq!(fs.alloc_data_range(200, 0).unwrap().1, 200);
+        assert_eq!(fs.commit(), Err(Error::NoSpace));
+        assert_eq!(fs.stat_fs().free_blocks, free_blocks);
+        free_blocks
+    };
+    assert_eq!(EasyFileSystem::fsck(&efs, false), vec![]);
+    let image = ram.image();
+    assert_eq!(image[BLOCK_SZ + 4..BLOCK_SZ + 8], [0u8; 4]);
+    let ram: Arc<dyn BlockDevice> = Arc::new(RamBlockDevice::from_image(image));
+    let reopened = EasyFileSystem::open(ram, host_time, BLOCK_CACHE_SIZE).unwrap();
+    assert_eq!(reopened.lock().stat_fs().free_blocks, free_blocks);
+}
+
+#[test]
+fn sparse_files() {
+    let efs = EasyFileSystem::create(ram_image(), 8192, 1, BLOCK_SZ, host_time).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let greet_str = "Hello, world!";
+    let mut buffer = [0u8; 233];
+    // far more blocks than the image has would be needed otherwise
+    let sparse = root_inode.create("sparse").unwrap();
+    let far = 8_000_000usize;
+    assert_eq!(sparse.write_at(far, greet_str.as_bytes()), Ok(greet_str.len()));
+    assert_eq!(sparse.stat().unwrap().size as usize, far + greet_str.len());
+    let len = sparse.read_at(far, &mut buffer).unwrap();
+    assert_eq!(greet_str, core::str::from_utf8(&buffer[..len]).unwrap());
+    assert_eq!(sparse.read_at(far / 2, &mut buffer).unwrap(), buffer.len());
+    assert!(buffer.iter().all(|byte| *byte == 0));
+    sparse.write_at(3 * BLOCK_SZ, greet_str.as_bytes()).unwrap();
+    assert_eq!(sparse.read_at(3 * BLOCK_SZ, &mut buffer).unwrap(), buffer.len());
+    assert_eq!(greet_str.as_bytes(), &buffer[..greet_str.len()]);
+    assert_eq!(EasyFileSystem::fsck(&efs, false), vec![]);
+    root_inode.unlink("sparse").unwrap();
+    assert_eq!(EasyFileSystem::fsck(&efs, false), vec![]);
+}
+
+#[test]
+fn large_files() {
+    let efs = EasyFileSystem::create(ram_image(), 8192, 1, BLOCK_SZ, host_time).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let greet_str = "Hello, world!";
+    let mut buffer = [0u8; 233];
+    // triple indirect blocks and sizes past what 32 bits hold
+    let huge = root_inode.create("huge").unwrap();
+    let far = 100_000_000usize;
+    assert_eq!(huge.write_at(far, greet_str.as_bytes()), Ok(greet_str.len()));
+    let len = huge.read_at(far, &mut buffer).unwrap();
+    assert_eq!(greet_str, core::str::from_utf8(&buffer[..len]).unwrap());
+    assert_eq!(EasyFileSystem::fsck(&efs, false), vec![]);
+    let max_size = efs.lock().max_file_size() as usize;
+    assert!(max_size > 1 << 30);
+    assert_eq!(huge.write_at(max_size - 4, greet_str.as_bytes()), Ok(4));
+    assert_eq!(huge.write_at(max_size, greet_str.as_bytes()), Err(Error::FileTooLarge));
+    assert_eq!(huge.write_at(max_size + 1, &[]), Ok(0));
+    assert_eq!(huge.stat().unwrap().size as usize, max_size);
+    assert_eq!(huge.truncate(max_size + 1), Err(Error::FileTooLarge));
+    huge.truncate(far / 10).unwrap();
+    assert_eq!(huge.read_at(far, &mut buffer).unwrap(), 0);
+    assert_eq!(EasyFileSystem::fsck(&efs, false), vec![]);
+    root_inode.unlink("huge").unwrap();
+    assert_eq!(EasyFileSystem::fsck(&efs, false), vec![]);
+}
+
+#[test]
+fn truncate() {
+    let efs = EasyFileSystem::create(ram_image(), 8192, 1, BLOCK_SZ, host_time).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let before = efs.lock().stat_fs();
+    let log = root_inode.create("log").unwrap();
+    let data: Vec<u8> = (0..300 * BLOCK_SZ).map(|i| (i % 251) as u8 + 1).collect();
+    log.write_at(0, &data).unwrap();
+    for new_size in [200 * BLOCK_SZ + 7, 156 * BLOCK_SZ, 100 * BLOCK_SZ + 3, 20 * BLOCK_SZ, 5].iter() {
+        log.truncate(*new_size).unwrap();
+        assert_eq!(log.stat().unwrap().size as usize, *new_size);
+        let mut read_back = vec![0u8; 300 * BLOCK_SZ];
+        assert_eq!(log.read_at(0, &mut read_back).unwrap(), *new_size);
+        assert_eq!(&read_back[..*new_size], &data[..*new_size]);
+        assert_eq!(EasyFileSystem::fsck(&efs, false), vec![]);
+    }
+    // growing again reads back zeros past the old end
+    let mut buffer = [0u8; 233];
+    log.truncate(2 * BLOCK_SZ).unwrap();
+    assert_eq!(log.read_at(0, &mut buffer).unwrap(), buffer.len());
+    assert_eq!(&buffer[..5], &data[..5]);
+    assert!(buffer[5..].iter().all(|byte| *byte == 0));
+    root_inode.unlink("log").unwrap();
+    assert_eq!(efs.lock().stat_fs(), before);
+    // only regular files can be truncated
+    let dir = root_inode.create_dir("dir").unwrap();
+    let link = root_inode.symlink("link", "dir").unwrap();
+    assert_eq!(dir.truncate(0), Err(Error::NotFile));
+    assert_eq!(root_inode.truncate(BLOCK_SZ), Err(Error::NotFile));
+    assert_eq!(link.truncate(0), Err(Error::NotFile));
+    assert_eq!(link.readlink().as_deref(), Some("dir"));
+    assert_eq!(dir.ls().unwrap(), vec![".", ".."]);
+    assert_eq!(EasyFileSystem::fsck(&efs, false), vec![]);
+}
+
+#[test]
+fn fsck_repairs_leaks() {
+    let efs = EasyFileSystem::create(ram_image(), 8192, 1, BLOCK_SZ, host_time).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    root_inode.create_dir("bin").unwrap().create("file").unwrap().write_at(0, b"data").unwrap();
+    assert_eq!(EasyFileSystem::fsck(&efs, false), vec![]);
+    let leaked_block = efs.lock().alloc_data().unwrap();
+    let leaked_inode = efs.lock().alloc_inode().unwrap();
+    efs.lock().sync_all();
+    let problems = vec![
+        easy_fs::FsckProblem::LeakedInode(leaked_inode),
+        easy_fs::FsckProblem::LeakedBlock(leaked_block),
+    ];
+    assert_eq!(EasyFileSystem::fsck(&efs, true), problems);
+    assert_eq!(EasyFileSystem::fsck(&efs, false), vec![]);
+}
+
+#[test]
+fn free_counts() {
+    let efs = EasyFileSystem::create(ram_image(), 8192, 1, BLOCK_SZ, host_time).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let before = efs.lock().stat_fs();
+    assert_eq!(before.block_size, BLOCK_SZ);
+    assert!(before.free_blocks < before.total_blocks);
+    let counted = root_inode.create("counted").unwrap();
+    assert_eq!(counted.write_at(0, &[1u8; 3 * BLOCK_SZ]), Ok(3 * BLOCK_SZ));
+    let after = efs.lock().stat_fs();
+    assert_eq!(after.free_inodes, before.free_inodes - 1);
+    assert_eq!(after.free_blocks, before.free_blocks - 3);
+    root_inode.unlink("counted").unwrap();
+    assert_eq!(efs.lock().stat_fs(), before);
+}
+
+#[test]
+fn inline_data() {
+    let efs = EasyFileSystem::create(ram_image(), 8192, 1, BLOCK_SZ, host_time).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let before = efs.lock().stat_fs();
+    // tiny files take no data block until they grow
+    let tiny = root_inode.create("tiny").unwrap();
+    let marker = b"marker file of a few bytes";
+    assert_eq!(tiny.write_at(0, marker), Ok(marker.len()));
+    assert_eq!(efs.lock().stat_fs().free_blocks, before.free_blocks);
+    let mut buffer = [0u8; 200];
+    assert_eq!(tiny.read_at(0, &mut buffer).unwrap(), marker.len());
+    assert_eq!(&buffer[..marker.len()], marker);
+    tiny.truncate(10).unwrap();
+    tiny.truncate(150).unwrap();
+    assert_eq!(tiny.read_at(0, &mut buffer).unwrap(), 150);
+    assert_eq!(&buffer[..10], &marker[..10]);
+    assert!(buffer[10..150].iter().all(|byte| *byte == 0));
+    assert_eq!(efs.lock().stat_fs().free_blocks, before.free_blocks);
+    assert_eq!(tiny.write_at(140, &[7u8; 60]), Ok(60));
+    assert_eq!(efs.lock().stat_fs().free_blocks, before.free_blocks - 1);
+    assert_eq!(tiny.read_at(0, &mut buffer).unwrap(), 200);
+    assert_eq!(&buffer[..10], &marker[..10]);
+    assert!(buffer[10..140].iter().all(|byte| *byte == 0));
+    assert!(buffer[140..].iter().all(|byte| *byte == 7));
+    assert_eq!(EasyFileSystem::fsck(&efs, false), vec![]);
+    let link = root_inode.symlink("tiny_link", "tiny").unwrap();
+    assert_eq!(link.readlink(), Some(String::from("tiny")));
+    root_inode.unlink("tiny_link").unwrap();
+    root_inode.unlink("tiny").unwrap();
+    assert_eq!(efs.lock().stat_fs(), before);
+}
+
+#[test]
+fn xattrs() {
+    let efs = EasyFileSystem::create(ram_image(), 8192, 1, BLOCK_SZ, host_time).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let before = efs.lock().stat_fs();
+    // extended attributes take a block of their own, freed with the inode
+    let tiny = root_inode.create("tiny").unwrap();
+    assert_eq!(tiny.set_xattr("exit_code", b"0"), Ok(()));
+    assert_eq!(tiny.set_xattr("timeout", b"10s"), Ok(()));
+    assert_eq!(efs.lock().stat_fs().free_blocks, before.free_blocks - 1);
+    assert_eq!(tiny.set_xattr("exit_code", b"-11"), Ok(()));
+    assert_eq!(tiny.get_xattr("exit_code"), Ok(b"-11".to_vec()));
+    assert_eq!(tiny.list_xattr().unwrap(), vec!["exit_code", "timeout"]);
+    assert_eq!(tiny.get_xattr("missing"), Err(Error::NotFound));
+    assert_eq!(tiny.remove_xattr("missing"), Err(Error::NotFound));
+    assert_eq!(tiny.set_xattr("", b""), Err(Error::InvalidName));
+    assert_eq!(tiny.set_xattr("big", &[0u8; BLOCK_SZ]), Err(Error::NoSpace));
+    assert_eq!(tiny.remove_xattr("exit_code"), Ok(()));
+    assert_eq!(tiny.list_xattr().unwrap(), vec!["timeout"]);
+    assert_eq!(EasyFileSystem::fsck(&efs, false), vec![]);
+    root_inode.unlink("tiny").unwrap();
+    assert_eq!(efs.lock().stat_fs(), before);
+}
+
+#[test]
+fn compression() {
+    let efs = EasyFileSystem::create(ram_image(), 8192, 1, BLOCK_SZ, host_time).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let before = efs.lock().stat_fs();
+    // compressed files take fewer blocks and read back as written
+    let packed = root_inode.create("packed").unwrap();
+    assert_eq!(packed.set_compressed(), Ok(()));
+    assert!(packed.stat().unwrap().compressed);
+    let mut expected: Vec<u8> = (0..20 * BLOCK_SZ).map(|i| b"easy-fs packs apps "[i % 19]).collect();
+    assert_eq!(packed.write_at(0, &expected), Ok(expected.len()));
+    assert!(efs.lock().stat_fs().free_blocks > before.free_blocks - 5);
+    let mut buffer = vec![0u8; 24 * BLOCK_SZ];
+    assert_eq!(packed.read_at(0, &mut buffer), Ok(expected.len()));
+    assert_eq!(&buffer[..expected.len()], expected.as_slice());
+    // data that does not compress is kept as it is
+    let mut seed = 1u32;
+    let noise: Vec<u8> = (0..14 * BLOCK_SZ)
+        .map(|_| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16) as u8
+        })
+        .collect();
+    assert_eq!(packed.write_at(3 * BLOCK_SZ + 7, &noise), Ok(noise.len()));
+    expected[3 * BLOCK_SZ + 7..17 * BLOCK_SZ + 7].copy_from_slice(&noise);
+    assert_eq!(packed.read_at(0, &mut buffer), Ok(expected.len()));
+    assert_eq!(&buffer[..expected.len()], expected.as_slice());
+    for offset in (0..expected.len()).step_by(333) {
+        let mut small = [0u8; 100];
+        let len = packed.read_at(offset, &mut small).unwrap();
+        assert_eq!(&small[..len], &expected[offset..offset + len]);
+    }
+    // truncated data reads back as zeros once the file grows again
+    packed.truncate(10 * BLOCK_SZ - 1).unwrap();
+    packed.truncate(24 * BLOCK_SZ).unwrap();
+    assert_eq!(packed.read_at(0, &mut buffer), Ok(24 * BLOCK_SZ));
+    assert_eq!(&buffer[..10 * BLOCK_SZ - 1], &expected[..10 * BLOCK_SZ - 1]);
+    assert!(buffer[10 * BLOCK_SZ - 1..].iter().all(|byte| *byte == 0));
+    packed.truncate(5 * BLOCK_SZ + 10).unwrap();
+    assert_eq!(packed.write_at(10 * BLOCK_SZ - 1, &[9u8]), Ok(1));
+    assert_eq!(packed.read_at(0, &mut buffer), Ok(10 * BLOCK_SZ));
+    assert_eq!(&buffer[..5 * BLOCK_SZ + 10], &expected[..5 * BLOCK_SZ + 10]);
+    assert!(buffer[5 * BLOCK_SZ + 10..10 * BLOCK_SZ - 1].iter().all(|byte| *byte == 0));
+    assert_eq!(buffer[10 * BLOCK_SZ - 1], 9);
+    assert_eq!(EasyFileSystem::fsck(&efs, false), vec![]);
+    // only empty files can be compressed
+    assert_eq!(packed.set_compressed(), Err(Error::Unsupported));
+    assert_eq!(root_inode.set_compressed(), Err(Error::Unsupported));
+    root_inode.unlink("packed").unwrap();
+    assert_eq!(efs.lock().stat_fs(), before);
+}
+
+#[test]
+fn directory_index() {
+    let efs = EasyFileSystem::create(ram_image(), 8192, 1, BLOCK_SZ, host_time).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let before = efs.lock().stat_fs();
+    // large directories are looked up through their index
+    let many = root_inode.create_dir("many").unwrap();
+    for i in 0..1500 {
+        many.create(&format!("fixture{}", i)).unwrap();
+    }
+    assert_eq!(many.create("fixture42").err(), Some(Error::Exists));
+    for i in (0..1500).step_by(3) {
+        many.unlink(&format!("fixture{}", i)).unwrap();
+    }
+    for i in 0..1500 {
+        assert_eq!(many.find(&format!("fixture{}", i)).is_ok(), i % 3 != 0);
+    }
+    assert_eq!(EasyFileSystem::fsck(&efs, false), vec![]);
+    // freed slots are taken again, long names included
+    let size = many.stat().unwrap().size;
+    for i in (0..1500).step_by(3) {
+        many.create(&format!("fixture{}", i)).unwrap();
+    }
+    let long_name = "l".repeat(100);
+    many.create(&long_name).unwrap();
+    assert!(many.stat().unwrap().size <= size + 4 * 32);
+    assert!(many.find(&long_name).is_ok());
+    assert_eq!(many.ls().unwrap().len(), 1500 + 3);
+    assert_eq!(EasyFileSystem::fsck(&efs, false), vec![]);
+    for name in many.ls().unwrap().iter().filter(|name| *name != "." && *name != "..") {
+        many.unlink(name).unwrap();
+    }
+    root_inode.rmdir("many").unwrap();
+    assert_eq!(efs.lock().stat_fs(), before);
+}
+
+#[test]
+fn corrupt_directory_index() {
+    let ram = ram_image();
+    let (many_id, (many_block, many_offset)) = {
+        let efs = EasyFileSystem::create(ram.clone(), 8192, 1, BLOCK_SZ, host_time).unwrap();
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        let many = root_inode.create_dir("many").unwrap();
+        for i in 0..100 {
+            many.create(&format!("fixture{}", i)).unwrap();
+        }
+        let pos = efs.lock().get_disk_inode_pos(many.inode_id());
+        (many.inode_id(), pos)
+    };
+    // the leaf holding the key of a name, found by the FNV-1a hash of it
+    let hash = "fixture7".bytes().fold(0x811c9dc5u32, |hash, byte| {
+        (hash ^ byte as u32).wrapping_mul(0x01000193)
+    });
+    let word = |node: &[u8], i: usize| {
+        u32::from_le_bytes([node[4 * i], node[4 * i + 1], node[4 * i + 2], node[4 * i + 3]])
+    };
+    let image = ram.image();
+    let leaf = image
+        .chunks(BLOCK_SZ)
+        .rposition(|node| {
+            word(node, 0) == 0 && (0..word(node, 1).min(41) as usize).any(|i| word(node, 2 + 3 * i) == hash)
+        })
+        .unwrap();
+    let open = |image: Vec<u8>| {
+        let ram: Arc<dyn BlockDevice> = Arc::new(RamBlockDevice::from_image(image));
+        EasyFileSystem::open(ram, host_time, BLOCK_CACHE_SIZE).unwrap()
+    };
+    // index nodes carry checksums like directory blocks
+    let mut rotten = image.clone();
+    rotten[leaf * BLOCK_SZ + 9] ^= 0x10;
+    let efs = open(rotten);
+    let many = EasyFileSystem::root_inode(&efs).find("many").unwrap();
+    assert_eq!(many.find("fixture7").err(), Some(Error::Corrupt));
+    assert!(EasyFileSystem::fsck(&efs, false).contains(&easy_fs::FsckProblem::BadDirIndex(many_id)));
+    // a node referring to itself is caught by its level rather than
+    // followed forever, on an image from before checksums
+    let mut cyclic = image.clone();
+    cyclic[28..32].copy_from_slice(&6u32.to_le_bytes());
+    let node = &mut cyclic[leaf * BLOCK_SZ..(leaf + 1) * BLOCK_SZ];
+    node[..4].copy_from_slice(&1u32.to_le_bytes());
+    for i in 0..word(node, 1) as usize {
+        node[4 * (4 + 3 * i)..4 * (5 + 3 * i)].copy_from_slice(&(leaf as u32).to_le_bytes());
+    }
+    let efs = open(cyclic);
+    let many = EasyFileSystem::root_inode(&efs).find("many").unwrap();
+    assert_eq!(many.find("fixture7").err(), Some(Error::Corrupt));
+    assert!(EasyFileSystem::fsck(&efs, false).contains(&easy_fs::FsckProblem::BadDirIndex(many_id)));
+    // nor is a node every entry of its parent refers to walked once per
+    // entry, 41 to the 15th times down a chain of them
+    let mut shared = image;
+    shared[28..32].copy_from_slice(&6u32.to_le_bytes());
+    let chain = 8100;
+    for level in 0..16 {
+        let node = &mut shared[(chain + level) * BLOCK_SZ..(chain + level + 1) * BLOCK_SZ];
+        node[..4].copy_from_slice(&(level as u32).to_le_bytes());
+        node[4..8].copy_from_slice(&41u32.to_le_bytes());
+        for i in 0..41 {
+            let entry = &mut node[8 + 12 * i..20 + 12 * i];
+            entry[..4].copy_from_slice(&hash.to_le_bytes());
+            if level > 0 {
+                entry[4..8].copy_from_slice(&(i as u32).to_le_bytes());
+                entry[8..].copy_from_slice(&((chain + level - 1) as u32).to_le_bytes());
+            }
+        }
+    }
+    let dir_index = many_block as usize * BLOCK_SZ + many_offset + 172;
+    shared[dir_index..dir_index + 4].copy_from_slice(&((chain + 15) as u32).to_le_bytes());
+    let efs = open(shared);
+    let many = EasyFileSystem::root_inode(&efs).find("many").unwrap();
+    assert_eq!(many.find("fixture7").err(), Some(Error::Corrupt));
+    assert!(EasyFileSystem::fsck(&efs, false).contains(&easy_fs::FsckProblem::BadDirIndex(many_id)));
+}
+
+#[test]
+fn block_sizes() -> std::io::Result<()> {
+    let greet_str = "Hello, world!";
+    let efs = EasyFileSystem::create(ram_image(), 8192, 1, BLOCK_SZ, host_time).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let filea = root_inode.create("filea").unwrap();
+    filea.write_at(0, &[1u8; 20 * BLOCK_SZ]).unwrap();
+    // blocks of different devices do not alias in the cache, even when
+    // their block sizes differ
+    let big_block = 4096;
+    let other_file = Arc::new(BlockFile(Mutex::new({
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open("target/fs2.img")?;
+        f.set_len(4096 * big_block as u64).unwrap();
+        f
+    })));
+    EasyFileSystem::create(other_file.clone(), 4096, 1, big_block, host_time).unwrap();
+    let other_efs = EasyFileSystem::open(other_file.clone(), host_time, BLOCK_CACHE_SIZE).unwrap();
+    assert_eq!(other_efs.lock().block_size(), big_block);
+    assert!(other_efs.lock().max_file_size() > efs.lock().max_file_size());
+    let other_root = EasyFileSystem::root_inode(&other_efs);
+    assert_eq!(other_root.find("filea").err(), Some(Error::NotFound));
+    let other_filea = other_root.create("filea").unwrap();
+    other_filea.write_at(0, greet_str.as_bytes()).unwrap();
+    other_filea.sync();
+    assert_eq!(filea.stat().unwrap().size as usize, 20 * BLOCK_SZ);
+    assert_eq!(other_filea.stat().unwrap().size as usize, greet_str.len());
+    let mut read_back = vec![0u8; 20 * BLOCK_SZ];
+    assert_eq!(filea.read_at(0, &mut read_back), Ok(20 * BLOCK_SZ));
+    assert!(read_back.iter().all(|byte| *byte == 1));
+    // past the direct blocks and into indirect2 with 4KiB blocks
+    let data: Vec<u8> = (0..1100 * big_block).map(|i| (i % 253) as u8).collect();
+    let other_fileb = other_root.create("fileb").unwrap();
+    assert_eq!(other_fileb.write_at(0, &data), Ok(data.len()));
+    let mut read_back = vec![0u8; data.len()];
+    assert_eq!(other_fileb.read_at(0, &mut read_back).unwrap(), data.len());
+    assert!(read_back == data);
+    other_fileb.truncate(3 * big_block + 1).unwrap();
+    assert_eq!(EasyFileSystem::fsck(&efs, false), vec![]);
+    assert_eq!(EasyFileSystem::fsck(&other_efs, false), vec![]);
+    Ok(())
+}
+
+#[test]
+fn out_of_space() {
+    // images too small for their own metadata and a root directory are refused
+    for total_blocks in [64, 1090, 1091].iter() {
+        let created = EasyFileSystem::create(ram_image(), *total_blocks, 1, BLOCK_SZ, host_time);
+        assert_eq!(created.err(), Some(Error::NoSpace));
+    }
+    let created = EasyFileSystem::create(ram_image(), 8192, 1, 1000, host_time);
+    assert_eq!(created.err(), Some(Error::Unsupported));
+    let efs = EasyFileSystem::create(Arc::new(RamBlockDevice::new(4096)), 4096, 1, BLOCK_SZ, host_time).unwrap();
+    let root = EasyFileSystem::root_inode(&efs);
+    let file = root.create("file").unwrap();
+    // running out of space gives a short write, then errors
+    let fill = root.create("fill").unwrap();
+    let data = vec![0x5au8; 4096 * BLOCK_SZ];
+    let written = fill.write_at(0, &data).unwrap();
+    assert!(written > 0 && written < data.len());
+    assert_eq!(fill.stat().unwrap().size as usize, written);
+    assert_eq!(fill.write_at(written, &data[..BLOCK_SZ]), Err(Error::NoSpace));
+    assert_eq!(root.create_dir("full").err(), Some(Error::NoSpace));
+    assert_eq!(root.find("full").err(), Some(Error::NotFound));
+    assert_eq!(file.find("x").err(), Some(Error::NotDir));
+    assert_eq!(EasyFileSystem::fsck(&efs, false), vec![]);
+    fill.truncate(written - BLOCK_SZ).unwrap();
+    root.create_dir("full").unwrap();
+    assert_eq!(EasyFileSystem::fsck(&efs, false), vec![]);
+}
+
+#[test]
+fn older_versions() {
+    let ram = ram_image();
+    let counts = {
+        let efs = EasyFileSystem::create(ram.clone(), 8192, 1, BLOCK_SZ, host_time).unwrap();
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        root_inode.create("file").unwrap().write_at(0, &[1u8; 3 * BLOCK_SZ]).unwrap();
+        let counts = efs.lock().stat_fs();
+        counts
+    };
+    // wrong free counts on disk are found and fixed by fsck, and images
+    // from before version 3 get theirs counted at open
+    let patched = |version: u32, free_inodes: u32, free_blocks: u32| {
+        let mut image = ram.image();
+        image[28..32].copy_from_slice(&version.to_le_bytes());
+        image[36..40].copy_from_slice(&free_inodes.to_le_bytes());
+        image[40..44].copy_from_slice(&free_blocks.to_le_bytes());
+        let ram: Arc<dyn BlockDevice> = Arc::new(RamBlockDevice::from_image(image));
+        EasyFileSystem::open(ram, host_time, BLOCK_CACHE_SIZE).unwrap()
+    };
+    let reopened = patched(5, 1, 2);
+    assert_eq!(
+        EasyFileSystem::fsck(&reopened, true),
+        vec![easy_fs::FsckProblem::WrongFreeCounts {
+            free_inodes: counts.free_inodes,
+            free_blocks: counts.free_blocks,
+        }]
+    );
+    assert_eq!(reopened.lock().stat_fs(), counts);
+    let reopened = patched(2, 0, 0);
+    assert_eq!(reopened.lock().stat_fs(), counts);
+    assert_eq!(EasyFileSystem::fsck(&reopened, false), vec![]);
+    let reopened_root = EasyFileSystem::root_inode(&reopened);
+    assert_eq!(reopened_root.set_xattr("tag", b""), Err(Error::Unsupported));
+    // images of the original layout have inodes of another size
+    let mut image = ram.image();
+    image[..4].copy_from_slice(&0x3b800001u32.to_le_bytes());
+    let original: Arc<dyn BlockDevice> = Arc::new(RamBlockDevice::from_image(image));
+    assert_eq!(
+        EasyFileSystem::open(original, host_time, BLOCK_CACHE_SIZE).err(),
+        Some(Error::Unsupported)
+    );
+}
+
+/// An image of 8192 blocks as easy-fs wrote them right before the SuperBlock
+/// recorded a version: 256-byte inodes, a 64-block journal and the root
+/// directory holding "." and "..".
+#[cfg(test)]
+fn unversioned_image() -> Vec<u8> {
+    let mut image = vec![0u8; 8192 * BLOCK_SZ];
+    // magic, total blocks, inode bitmap, inode area, data bitmap, data area
+    // and journal blocks
+    for (i, field) in [0x3b800001u32, 8192, 1, 2048, 2, 6076, 64].iter().enumerate() {
+        image[4 * i..4 * i + 4].copy_from_slice(&field.to_le_bytes());
+    }
+    // the root inode and its block are allocated
+    image[65 * BLOCK_SZ] = 1;
+    image[2114 * BLOCK_SZ] = 1;
+    // root inode: size, first direct block, type and nlink
+    let root_inode = 66 * BLOCK_SZ;
+    image[root_inode..root_inode + 4].copy_from_slice(&64u32.to_le_bytes());
+    image[root_inode + 4..root_inode + 8].copy_from_slice(&2116u32.to_le_bytes());
+    image[root_inode + 124] = 1;
+    image[root_inode + 126] = 1;
+    // dirents of inode 0: inode number, name length and name
+    for (slot, name) in [".", ".."].iter().enumerate() {
+        let dirent = 2116 * BLOCK_SZ + slot * 32;
+        image[dirent + 4] = name.len() as u8;
+        image[dirent + 5..dirent + 5 + name.len()].copy_from_slice(name.as_bytes());
+    }
+    image
+}
+
+#[test]
+fn version_zero() {
+    // images from before the SuperBlock recorded a version have the magic
+    // of the original layout, whatever their inodes look like
+    let old = Arc::new(RamBlockDevice::from_image(unversioned_image()));
+    assert_eq!(
+        EasyFileSystem::open(old, host_time, BLOCK_CACHE_SIZE).err(),
+        Some(Error::Unsupported)
+    );
+    // and no image with the current magic lacks a version
+    let ram = ram_image();
+    EasyFileSystem::create(ram.clone(), 8192, 1, BLOCK_SZ, host_time).unwrap();
+    let mut image = ram.image();
+    image[28..32].copy_from_slice(&0u32.to_le_bytes());
+    let unversioned = Arc::new(RamBlockDevice::from_image(image));
+    assert_eq!(
+        EasyFileSystem::open(unversioned, host_time, BLOCK_CACHE_SIZE).err(),
+        Some(Error::Corrupt)
+    );
+}
+
+#[test]
+fn corrupt_dirents() {
+    // a dirent name that is not UTF-8 is reported rather than panicked on,
+    // on an image from before checksums, which would catch it first
+    let ram = ram_image();
+    {
+        let efs = EasyFileSystem::create(ram.clone(), 8192, 1, BLOCK_SZ, host_time).unwrap();
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        root_inode.create("garbled").unwrap();
+    }
+    let mut image = ram.image();
+    image[28..32].copy_from_slice(&6u32.to_le_bytes());
+    let name = image.windows(7).rposition(|name| name == b"garbled").unwrap();
+    image[name] = 0xff;
+    let ram: Arc<dyn BlockDevice> = Arc::new(RamBlockDevice::from_image(image));
+    let efs = EasyFileSystem::open(ram, host_time, BLOCK_CACHE_SIZE).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    assert_eq!(root_inode.find("garbled").err(), Some(Error::Corrupt));
+    assert_eq!(root_inode.ls(), Err(Error::Corrupt));
+    assert_eq!(root_inode.create("other").err(), Some(Error::Corrupt));
+    assert_eq!(root_inode.unlink("garbled"), Err(Error::Corrupt));
+    assert_eq!(EasyFileSystem::fsck(&efs, false)[0], easy_fs::FsckProblem::CorruptInode(0));
+}
+
+#[test]
+fn wild_block_ids() {
+    // block ids outside of the data area, as a corrupt indirect block may
+    // hold on an image without checksums, are not freed
+    let ram = ram_image();
+    let (block_id, offset) = {
+        let efs = EasyFileSystem::create(ram.clone(), 8192, 1, BLOCK_SZ, host_time).unwrap();
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        let file = root_inode.create("wild").unwrap();
+        file.write_at(0, &[1u8; 40 * BLOCK_SZ]).unwrap();
+        let pos = efs.lock().get_disk_inode_pos(file.inode_id());
+        pos
+    };
+    let mut image = ram.image();
+    image[28..32].copy_from_slice(&6u32.to_le_bytes());
+    // indirect1 follows the size and the direct blocks
+    let indirect1 = block_id as usize * BLOCK_SZ + offset + 4 + 28 * 4;
+    let indirect1 = u32::from_le_bytes([
+        image[indirect1], image[indirect1 + 1], image[indirect1 + 2], image[indirect1 + 3],
+    ]) as usize * BLOCK_SZ;
+    image[indirect1..indirect1 + 4].copy_from_slice(&1u32.to_le_bytes());
+    image[indirect1 + 4..indirect1 + 8].copy_from_slice(&8192u32.to_le_bytes());
+    let ram: Arc<dyn BlockDevice> = Arc::new(RamBlockDevice::from_image(image));
+    let efs = EasyFileSystem::open(ram, host_time, BLOCK_CACHE_SIZE).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let free_blocks = efs.lock().stat_fs().free_blocks;
+    root_inode.unlink("wild").unwrap();
+    // the file, indirect1 included, less the two blocks it lost track of
+    assert_eq!(efs.lock().stat_fs().free_blocks, free_blocks + 39);
+}
+
+#[test]
+fn torn_writes() {
+    // a torn write only gets its first bytes through, later ones are lost
+    let ram = RamBlockDevice::new(4);
+    ram.write_block(0, &[1u8; BLOCK_SZ]);
+    ram.inject_fault(1, Fault::Torn(100));
+    ram.write_block(1, &[2u8; BLOCK_SZ]);
+    ram.write_block(2, &[3u8; BLOCK_SZ]);
+    ram.write_block(3, &[4u8; BLOCK_SZ]);
+    assert_eq!(ram.writes(), 3);
+    let mut sector = [0u8; BLOCK_SZ];
+    ram.read_block(1, &mut sector);
+    assert!(sector.iter().all(|byte| *byte == 2));
+    ram.read_block(2, &mut sector);
+    assert!(sector[..100].iter().all(|byte| *byte == 3));
+    assert!(sector[100..].iter().all(|byte| *byte == 0));
+    ram.read_block(3, &mut sector);
+    assert!(sector.iter().all(|byte| *byte == 0));
+}
+
+#[test]
+fn crash_consistency() {
+    // power lost at any write, or in the middle of it, leaves an image
+    // that opens consistent, with each operation either done or not
+    let base = {
+        let ram = Arc::new(RamBlockDevice::new(4096));
+        let efs = EasyFileSystem::create(ram.clone(), 4096, 1, BLOCK_SZ, host_time).unwrap();
+        let root = EasyFileSystem::root_inode(&efs);
+        root.create("a").unwrap().write_at(0, b"crash").unwrap();
+        ram.snapshot()
+    };
+    let data: Vec<u8> = (0..20 * BLOCK_SZ).map(|i| i as u8).collect();
+    for fault in [Fault::Fail, Fault::Torn(100)].iter() {
+        for writes in 0.. {
+            let ram = Arc::new(base.snapshot());
+            {
+                let efs = EasyFileSystem::open(ram.clone(), host_time, BLOCK_CACHE_SIZE).unwrap();
+                let root = EasyFileSystem::root_inode(&efs);
+                ram.inject_fault(writes, *fault);
+                root.create("b").unwrap().write_at(0, &data).unwrap();
+                root.rename("a", &root, "c").unwrap();
+            }
+            let done = ram.writes() < writes;
+            let crashed = Arc::new(ram.snapshot());
+            let efs = EasyFileSystem::open(crashed, host_time, BLOCK_CACHE_SIZE).unwrap();
+            assert_eq!(EasyFileSystem::fsck(&efs, false), vec![]);
+            let root = EasyFileSystem::root_inode(&efs);
+            let renamed = root.find("c").is_ok();
+            assert_ne!(root.find("a").is_ok(), renamed);
+            if let Ok(b) = root.find("b") {
+                let size = b.stat().unwrap().size as usize;
+                assert!(!renamed || size == data.len());
+                let mut read_back = vec![0u8; size];
+                b.read_at(0, &mut read_back).unwrap();
+                assert!(read_back[..] == data[..size]);
+            } else {
+                assert!(!renamed);
+            }
+            if done {
+                assert!(renamed);
+                break;
+            }
+        }
+    }
+}
+
+#[test]
+fn contiguous_allocation() {
+    // a file lands in one run of blocks even where freed blocks are
+    // scattered before it
+    let ram = Arc::new(RamBlockDevice::new(4096));
+    {
+        let efs = EasyFileSystem::create(ram.clone(), 4096, 1, BLOCK_SZ, host_time).unwrap();
+        let root = EasyFileSystem::root_inode(&efs);
+        for i in 0..10 {
+            root.create(&format!("small{}", i)).unwrap().write_at(0, &[0xffu8; BLOCK_SZ]).unwrap();
+        }
+        for i in (0..10).step_by(2) {
+            root.unlink(&format!("small{}", i)).unwrap();
+        }
+        let data: Vec<u8> = (0..24 * BLOCK_SZ).map(|i| (i / BLOCK_SZ + 1) as u8).collect();
+        assert_eq!(root.create("big").unwrap().write_at(0, &data), Ok(data.len()));
+    }
+    // the journal may keep copies, the data area comes after it
+    let image = ram.image();
+    let sector_of = |tag: u8| {
+        image.chunks(BLOCK_SZ).rposition(|sector| sector.iter().all(|byte| *byte == tag)).unwrap()
+    };
+    let first = sector_of(1);
+    assert!((2..=24).all(|tag| sector_of(tag) == first + tag as usize - 1));
+}
+
+#[test]
+fn checksums() {
+    // bit rot in metadata is reported rather than read as garbage
+    let ram = Arc::new(RamBlockDevice::new(4096));
+    let (inode_sector, inode_bitmap_sector, data_bitmap_sector) = {
+        let efs = EasyFileSystem::create(ram.clone(), 4096, 1, BLOCK_SZ, host_time).unwrap();
+        let root = EasyFileSystem::root_inode(&efs);
+        let file = root.create_dir("rotdir").unwrap().create("rotfile").unwrap();
+        file.write_at(0, b"rot").unwrap();
+        let fs = efs.lock();
+        (
+            fs.get_disk_inode_pos(file.inode_id()).0 as usize,
+            fs.inode_bitmap.block_id(0),
+            fs.data_bitmap.block_id(0),
+        )
+    };
+    let image = ram.image();
+    let root_dir_sector = image
+        .chunks(BLOCK_SZ)
+        .rposition(|sector| sector.windows(6).any(|name| name == b"rotdir"))
+        .unwrap();
+    let flip = |sector: usize, byte: usize| {
+        let mut image = image.clone();
+        image[sector * BLOCK_SZ + byte] ^= 0x10;
+        let ram: Arc<dyn BlockDevice> = Arc::new(RamBlockDevice::from_image(image));
+        ram
+    };
+    assert_eq!(EasyFileSystem::open(flip(0, 100), host_time, BLOCK_CACHE_SIZE).err(), Some(Error::Corrupt));
+    let open = |ram| EasyFileSystem::open(ram, host_time, BLOCK_CACHE_SIZE).unwrap();
+    let efs = open(flip(inode_sector, 20));
+    let root = EasyFileSystem::root_inode(&efs);
+    let file = root.find("rotdir").unwrap().find("rotfile").unwrap();
+    assert_eq!(file.stat().err(), Some(Error::Corrupt));
+    assert_eq!(file.read_at(0, &mut [0u8; 3]), Err(Error::Corrupt));
+    assert_eq!(file.write_at(0, b"new"), Err(Error::Corrupt));
+    assert_eq!(
+        EasyFileSystem::fsck(&efs, false),
+        vec![easy_fs::FsckProblem::CorruptInode(file.inode_id())]
+    );
+    let efs = open(flip(root_dir_sector, 200));
+    let root = EasyFileSystem::root_inode(&efs);
+    assert_eq!(root.find("rotdir").err(), Some(Error::Corrupt));
+    assert_eq!(root.ls(), Err(Error::Corrupt));
+    assert_eq!(root.create("other").err(), Some(Error::Corrupt));
+    assert_eq!(root.unlink("rotdir"), Err(Error::Corrupt));
+    // what lies behind it passes for leaked, it is not freed for all that
+    let problems = EasyFileSystem::fsck(&efs, true);
+    assert_eq!(problems[0], easy_fs::FsckProblem::CorruptInode(0));
+    assert!(problems.contains(&easy_fs::FsckProblem::LeakedInode(1)));
+    assert_eq!(EasyFileSystem::fsck(&efs, true), problems);
+    // nothing gets freed on behalf of a corrupt bitmap
+    let efs = open(flip(data_bitmap_sector, 0));
+    let root = EasyFileSystem::root_inode(&efs);
+    let free_blocks = efs.lock().stat_fs().free_blocks;
+    assert_eq!(root.create_dir("other").err(), Some(Error::Corrupt));
+    root.find("rotdir").unwrap().unlink("rotfile").unwrap();
+    assert_eq!(efs.lock().stat_fs().free_blocks, free_blocks);
+    assert!(EasyFileSystem::fsck(&efs, true)
+        .contains(&easy_fs::FsckProblem::CorruptBitmap(data_bitmap_sector as u32)));
+    let efs = open(flip(inode_bitmap_sector, 0));
+    let root = EasyFileSystem::root_inode(&efs);
+    assert_eq!(root.create("other").err(), Some(Error::Corrupt));
+    assert!(EasyFileSystem::fsck(&efs, false)
+        .contains(&easy_fs::FsckProblem::CorruptBitmap(inode_bitmap_sector as u32)));
+}
@@ -1,73 +1,199 @@
 use alloc::sync::Arc;
+use spin::Mutex;
 use super::{
     BlockDevice,
-    BLOCK_SZ,
+    BlockCache,
+    SuperBlock,
+    Error,
+    Result,
+    crc32,
     get_block_cache,
 };
 
-type BitmapBlock = [u64; 64];
-
-const BLOCK_BITS: usize = BLOCK_SZ * 8;
+type BitmapBlock = [u64];
 
 pub struct Bitmap {
     start_block_id: usize,
     blocks: usize,
-}
-
-/// Return (block_pos, bits64_pos, inner_pos)
-fn decomposition(mut bit: usize) -> (usize, usize, usize) {
-    let block_pos = bit / BLOCK_BITS;
-    bit = bit % BLOCK_BITS;
-    (block_pos, bit / 64, bit % 64)
+    /// Bits held by one block.
+    block_bits: usize,
+    /// Where the SuperBlock keeps the checksum of the first block, None on
+    /// images without checksums.
+    checksum_index: Option<usize>,
 }
 
 impl Bitmap {
-    pub fn new(start_block_id: usize, blocks: usize) -> Self {
+    pub fn new(
+        start_block_id: usize,
+        blocks: usize,
+        block_size: usize,
+        checksum_index: Option<usize>,
+    ) -> Self {
         Self {
             start_block_id,
             blocks,
+            block_bits: block_size * 8,
+            checksum_index,
+        }
+    }
+
+    /// Block `block_pos` of the bitmap, verified against its checksum.
+    fn block(
+        &self,
+        block_pos: usize,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Result<Arc<Mutex<BlockCache>>> {
+        let block_cache = get_block_cache(block_pos + self.start_block_id, Arc::clone(block_device));
+        if let Some(idx) = self.checksum_index {
+            block_cache.lock().verify(|bitmap_block| {
+                let expected = get_block_cache(0, Arc::clone(block_device))
+                    .lock()
+                    .read(0, |super_block: &SuperBlock| super_block.bitmap_checksum(idx + block_pos));
+                expected.iter().all(|checksum| *checksum == crc32(bitmap_block))
+            })?;
         }
+        Ok(block_cache)
     }
 
-    pub fn alloc(&self, block_device: &Arc<dyn BlockDevice>) -> Option<usize> {
-        for block_id in 0..self.blocks {
-            let pos = get_block_cache(
-                block_id + self.start_block_id as usize,
-                Arc::clone(block_device),
-            ).lock().modify(0, |bitmap_block: &mut BitmapBlock| {
-                if let Some((bits64_pos, inner_pos)) = bitmap_block
-                    .iter()
-                    .enumerate()
-                    .find(|(_, bits64)| **bits64 != u64::MAX)
-                    .map(|(bits64_pos, bits64)| {
-                        (bits64_pos, bits64.trailing_ones() as usize)
-                    }) {
-                    // modify cache
+    /// Keep the checksum of block `block_pos` up to date after a change.
+    fn update_checksum(
+        &self,
+        block_pos: usize,
+        block_cache: &Arc<Mutex<BlockCache>>,
+        block_device: &Arc<dyn BlockDevice>,
+    ) {
+        if let Some(idx) = self.checksum_index {
+            let checksum = block_cache.lock().read_slice(0, |bitmap_block: &[u8]| crc32(bitmap_block));
+            get_block_cache(0, Arc::clone(block_device))
+                .lock()
+                .modify(0, |super_block: &mut SuperBlock| {
+                    super_block.set_bitmap_checksum(idx + block_pos, checksum);
+                });
+        }
+    }
+
+    /// Return (block_pos, bits64_pos, inner_pos)
+    fn decomposition(&self, mut bit: usize) -> (usize, usize, usize) {
+        let block_pos = bit / self.block_bits;
+        bit %= self.block_bits;
+        (block_pos, bit / 64, bit % 64)
+    }
+
+    pub fn alloc(&self, block_device: &Arc<dyn BlockDevice>) -> Result<usize> {
+        self.alloc_range(block_device, 1, 0).map(|(bit, _)| bit)
+    }
+
+    /// Allocate the first free bit at or after `hint`, wrapping around,
+    /// along with the free bits right after it, `n` bits at most. Return
+    /// the first bit and how many were allocated.
+    pub fn alloc_range(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        n: usize,
+        hint: usize,
+    ) -> Result<(usize, usize)> {
+        let hint = if hint < self.maximum() { hint } else { 0 };
+        let hint_block = hint / self.block_bits;
+        // the block of the hint comes again last for the bits before it
+        for i in 0..=self.blocks {
+            let block_pos = (hint_block + i) % self.blocks;
+            let from = if i == 0 { hint % self.block_bits } else { 0 };
+            let block_cache = self.block(block_pos, block_device)?;
+            // full blocks are only read, so that they stay out of the transaction
+            let first = block_cache.lock().read_slice(0, |bitmap_block: &BitmapBlock| {
+                first_free(bitmap_block, from)
+            });
+            let first = match first {
+                Some(first) => first,
+                None => continue,
+            };
+            let len = block_cache.lock().modify_slice(0, |bitmap_block: &mut BitmapBlock| {
+                // a run ends at a bit in use or with the block
+                let mut len = 0usize;
+                while len < n && first + len < self.block_bits {
+                    let (bits64_pos, inner_pos) = ((first + len) / 64, (first + len) % 64);
+                    if bitmap_block[bits64_pos] & (1u64 << inner_pos) > 0 {
+                        break;
+                    }
                     bitmap_block[bits64_pos] |= 1u64 << inner_pos;
-                    Some(block_id * BLOCK_BITS + bits64_pos * 64 + inner_pos as usize)
-                } else {
-                    None
+                    len += 1;
                 }
+                len
             });
-            if pos.is_some() {
-                return pos;
-            }
+            self.update_checksum(block_pos, &block_cache, block_device);
+            return Ok((block_pos * self.block_bits + first, len));
         }
-        None
+        Err(Error::NoSpace)
     }
 
-    pub fn dealloc(&self, block_device: &Arc<dyn BlockDevice>, bit: usize) {
-        let (block_pos, bits64_pos, inner_pos) = decomposition(bit);
-        get_block_cache(
-            block_pos + self.start_block_id,
-            Arc::clone(block_device)
-        ).lock().modify(0, |bitmap_block: &mut BitmapBlock| {
-            assert!(bitmap_block[bits64_pos] & (1u64 << inner_pos) > 0);
+    /// Free `bit`. On a corrupt block it is left allocated, which at worst
+    /// leaks what it stands for. A bit that is free already means the
+    /// bitmap and whatever freed it disagree, it fails as corrupt too, as
+    /// does a bit past the bitmap.
+    pub fn dealloc(&self, block_device: &Arc<dyn BlockDevice>, bit: usize) -> Result<()> {
+        if bit >= self.maximum() {
+            return Err(Error::Corrupt);
+        }
+        let (block_pos, bits64_pos, inner_pos) = self.decomposition(bit);
+        let block_cache = self.block(block_pos, block_device)?;
+        let allocated = block_cache.lock().read_slice(0, |bitmap_block: &BitmapBlock| {
+            bitmap_block[bits64_pos] & (1u64 << inner_pos) > 0
+        });
+        if !allocated {
+            return Err(Error::Corrupt);
+        }
+        block_cache.lock().modify_slice(0, |bitmap_block: &mut BitmapBlock| {
             bitmap_block[bits64_pos] -= 1u64 << inner_pos;
         });
+        self.update_checksum(block_pos, &block_cache, block_device);
+        Ok(())
+    }
+
+    pub fn is_allocated(&self, block_device: &Arc<dyn BlockDevice>, bit: usize) -> Result<bool> {
+        let (block_pos, bits64_pos, inner_pos) = self.decomposition(bit);
+        let allocated = self.block(block_pos, block_device)?
+            .lock()
+            .read_slice(0, |bitmap_block: &BitmapBlock| {
+                bitmap_block[bits64_pos] & (1u64 << inner_pos) > 0
+            });
+        Ok(allocated)
+    }
+
+    /// Count bits set, for images that do not keep free counts.
+    pub fn allocated(&self, block_device: &Arc<dyn BlockDevice>) -> Result<usize> {
+        let mut allocated = 0usize;
+        for block_pos in 0..self.blocks {
+            allocated += self.block(block_pos, block_device)?
+                .lock()
+                .read_slice(0, |bitmap_block: &BitmapBlock| {
+                    bitmap_block.iter().map(|bits64| bits64.count_ones() as usize).sum::<usize>()
+                });
+        }
+        Ok(allocated)
+    }
+
+    /// Block holding `bit`.
+    pub fn block_id(&self, bit: usize) -> usize {
+        self.start_block_id + bit / self.block_bits
     }
 
     pub fn maximum(&self) -> usize {
-        self.blocks * BLOCK_BITS
+        self.blocks * self.block_bits
     }
+}
+
+/// First free bit of a bitmap block at or after `from`.
+fn first_free(bitmap_block: &BitmapBlock, from: usize) -> Option<usize> {
+    (from / 64..bitmap_block.len()).find_map(|bits64_pos| {
+        let mut bits64 = bitmap_block[bits64_pos];
+        if bits64_pos == from / 64 {
+            // bits before from count as taken
+            bits64 |= (1u64 << (from % 64)) - 1;
+        }
+        if bits64 != u64::MAX {
+            Some(bits64_pos * 64 + bits64.trailing_ones() as usize)
+        } else {
+            None
+        }
+    })
 }
\ No newline at end of file
@@ -1,54 +1,91 @@
 use super::{
     BLOCK_SZ,
     BlockDevice,
+    Error,
+    Result,
 };
-use alloc::collections::VecDeque;
 use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
 use lazy_static::*;
 use spin::Mutex;
 
+fn as_bytes(words: &[u64]) -> &[u8] {
+    unsafe { core::slice::from_raw_parts(words.as_ptr() as *const u8, words.len() * 8) }
+}
+
+fn as_bytes_mut(words: &mut [u64]) -> &mut [u8] {
+    unsafe { core::slice::from_raw_parts_mut(words.as_mut_ptr() as *mut u8, words.len() * 8) }
+}
+
 pub struct BlockCache {
-    cache: [u8; BLOCK_SZ],
+    /// Kept in u64s so that whatever is read from it is aligned.
+    cache: Vec<u64>,
     block_id: usize,
     block_device: Arc<dyn BlockDevice>,
     modified: bool,
+    /// Whether the checksums of the block matched when it was verified
+    /// first, None until then.
+    checked: Option<bool>,
 }
 
 impl BlockCache {
-    /// Load a new BlockCache from disk.
+    /// Load a new BlockCache of `block_size` bytes from disk.
     pub fn new(
         block_id: usize, 
-        block_device: Arc<dyn BlockDevice>
+        block_device: Arc<dyn BlockDevice>,
+        block_size: usize,
     ) -> Self {
-        let mut cache = [0u8; BLOCK_SZ];
-        block_device.read_block(block_id, &mut cache);
+        let mut cache = vec![0u64; block_size / 8];
+        block_device.read_fs_block(block_id, as_bytes_mut(&mut cache));
         Self {
             cache,
             block_id,
             block_device,
             modified: false,
+            checked: None,
         }
     }
 
     fn addr_of_offset(&self, offset: usize) -> usize {
-        &self.cache[offset] as *const _ as usize
+        self.cache.as_ptr() as usize + offset
+    }
+
+    pub fn size(&self) -> usize {
+        self.cache.len() * 8
     }
 
     pub fn get_ref<T>(&self, offset: usize) -> &T where T: Sized {
         let type_size = core::mem::size_of::<T>();
-        assert!(offset + type_size <= BLOCK_SZ);
+        assert!(offset + type_size <= self.size());
         let addr = self.addr_of_offset(offset);
         unsafe { &*(addr as *const T) } 
     }
 
     pub fn get_mut<T>(&mut self, offset: usize) -> &mut T where T: Sized {
         let type_size = core::mem::size_of::<T>();
-        assert!(offset + type_size <= BLOCK_SZ);
+        assert!(offset + type_size <= self.size());
         self.modified = true;
         let addr = self.addr_of_offset(offset);
         unsafe { &mut *(addr as *mut T) }
     }
 
+    /// As many `T` as fit between `offset` and the end of the block.
+    pub fn get_slice<T>(&self, offset: usize) -> &[T] where T: Sized {
+        assert!(offset <= self.size());
+        let len = (self.size() - offset) / core::mem::size_of::<T>();
+        let addr = self.addr_of_offset(offset);
+        unsafe { core::slice::from_raw_parts(addr as *const T, len) }
+    }
+
+    pub fn get_slice_mut<T>(&mut self, offset: usize) -> &mut [T] where T: Sized {
+        assert!(offset <= self.size());
+        self.modified = true;
+        let len = (self.size() - offset) / core::mem::size_of::<T>();
+        let addr = self.addr_of_offset(offset);
+        unsafe { core::slice::from_raw_parts_mut(addr as *mut T, len) }
+    }
+
     pub fn read<T, V>(&self, offset: usize, f: impl FnOnce(&T) -> V) -> V {
         f(self.get_ref(offset))
     }
@@ -57,10 +94,48 @@ impl BlockCache {
         f(self.get_mut(offset))
     }
 
+    pub fn read_slice<T, V>(&self, offset: usize, f: impl FnOnce(&[T]) -> V) -> V {
+        f(self.get_slice(offset))
+    }
+
+    pub fn modify_slice<T, V>(&mut self, offset: usize, f: impl FnOnce(&mut [T]) -> V) -> V {
+        f(self.get_slice_mut(offset))
+    }
+
+    /// Check the block with `valid` the first time it is verified since it
+    /// was loaded. Blocks failing it stay corrupt until they are evicted.
+    pub fn verify(&mut self, valid: impl FnOnce(&[u8]) -> bool) -> Result<()> {
+        if self.checked.is_none() {
+            self.checked = Some(valid(as_bytes(&self.cache)));
+        }
+        match self.checked {
+            Some(true) => Ok(()),
+            _ => Err(Error::Corrupt),
+        }
+    }
+
+    pub fn block_id(&self) -> usize {
+        self.block_id
+    }
+
+    /// Whether the cache holds changes not written back yet.
+    pub fn is_dirty(&self) -> bool {
+        self.modified
+    }
+
     pub fn sync(&mut self) {
         if self.modified {
             self.modified = false;
-            self.block_device.write_block(self.block_id, &self.cache);
+            self.block_device.write_fs_block(self.block_id, as_bytes(&self.cache));
+        }
+    }
+
+    /// Drop the changes not written back yet and load the block again.
+    pub fn discard(&mut self) {
+        if self.modified {
+            self.modified = false;
+            self.checked = None;
+            self.block_device.read_fs_block(self.block_id, as_bytes_mut(&mut self.cache));
         }
     }
 }
@@ -71,15 +146,136 @@ impl Drop for BlockCache {
     }
 }
 
+/// Capacity until `EasyFileSystem::open` asks for another one.
 const BLOCK_CACHE_SIZE: usize = 16;
+/// End of the LRU list.
+const NIL: usize = usize::MAX;
+
+struct CacheEntry {
+    device: usize,
+    block_id: usize,
+    block_cache: Arc<Mutex<BlockCache>>,
+    /// More recently used neighbour.
+    prev: usize,
+    /// Less recently used neighbour.
+    next: usize,
+}
+
+/// Identify a device by the address it lives at, which cannot be taken by
+/// another device as long as its blocks are cached.
+fn device_key(block_device: &Arc<dyn BlockDevice>) -> usize {
+    Arc::as_ptr(block_device) as *const u8 as usize
+}
 
+/// Block caches indexed by (device, block id) through a hash table and
+/// kept in an LRU list. Beyond the capacity, the least recently used block
+/// nobody holds and with nothing to write back is evicted. When there is
+/// no such block the cache grows rather than failing.
 pub struct BlockCacheManager {
-    queue: VecDeque<(usize, Arc<Mutex<BlockCache>>)>,
+    capacity: usize,
+    entries: Vec<CacheEntry>,
+    buckets: Vec<Vec<usize>>,
+    /// Most recently used entry.
+    head: usize,
+    /// Least recently used entry.
+    tail: usize,
+    /// Block size of every device that does not use `BLOCK_SZ`.
+    block_sizes: Vec<(usize, usize)>,
 }
 
 impl BlockCacheManager {
     pub fn new() -> Self {
-        Self { queue: VecDeque::new() }
+        let mut manager = Self {
+            capacity: 0,
+            entries: Vec::new(),
+            buckets: Vec::new(),
+            head: NIL,
+            tail: NIL,
+            block_sizes: Vec::new(),
+        };
+        manager.set_capacity(BLOCK_CACHE_SIZE);
+        manager
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity.max(1);
+        self.buckets = vec![Vec::new(); self.capacity.next_power_of_two()];
+        for idx in 0..self.entries.len() {
+            let bucket = self.bucket(self.entries[idx].device, self.entries[idx].block_id);
+            self.buckets[bucket].push(idx);
+        }
+    }
+
+    fn block_size(&self, device: usize) -> usize {
+        self.block_sizes
+            .iter()
+            .find(|(key, _)| *key == device)
+            .map_or(BLOCK_SZ, |(_, block_size)| *block_size)
+    }
+
+    fn bucket(&self, device: usize, block_id: usize) -> usize {
+        let hash = (block_id ^ device.rotate_left(16)).wrapping_mul(0x9e37_79b9);
+        hash & (self.buckets.len() - 1)
+    }
+
+    fn find(&self, device: usize, block_id: usize) -> Option<usize> {
+        self.buckets[self.bucket(device, block_id)]
+            .iter()
+            .copied()
+            .find(|idx| {
+                let entry = &self.entries[*idx];
+                entry.device == device && entry.block_id == block_id
+            })
+    }
+
+    /// Take entry `idx` out of the LRU list.
+    fn detach(&mut self, idx: usize) {
+        let (prev, next) = (self.entries[idx].prev, self.entries[idx].next);
+        if prev == NIL { self.head = next; } else { self.entries[prev].next = next; }
+        if next == NIL { self.tail = prev; } else { self.entries[next].prev = prev; }
+    }
+
+    /// Put entry `idx` at the most recently used end of the LRU list.
+    fn attach(&mut self, idx: usize) {
+        self.entries[idx].prev = NIL;
+        self.entries[idx].next = self.head;
+        if self.head == NIL { self.tail = idx; } else { self.entries[self.head].prev = idx; }
+        self.head = idx;
+    }
+
+    /// Least recently used entry nobody holds and with nothing to write back.
+    fn victim(&self) -> Option<usize> {
+        let mut idx = self.tail;
+        while idx != NIL {
+            let block_cache = &self.entries[idx].block_cache;
+            // nobody else can lock a block cache we hold the only reference to
+            if Arc::strong_count(block_cache) == 1 && !block_cache.lock().is_dirty() {
+                return Some(idx);
+            }
+            idx = self.entries[idx].prev;
+        }
+        None
+    }
+
+    fn evict(&mut self, idx: usize) {
+        self.detach(idx);
+        let bucket = self.bucket(self.entries[idx].device, self.entries[idx].block_id);
+        self.buckets[bucket].retain(|i| *i != idx);
+        self.entries.swap_remove(idx);
+        // the last entry moved to idx
+        let moved = self.entries.len();
+        if idx == moved {
+            return;
+        }
+        let (prev, next) = (self.entries[idx].prev, self.entries[idx].next);
+        if prev == NIL { self.head = idx; } else { self.entries[prev].next = idx; }
+        if next == NIL { self.tail = idx; } else { self.entries[next].prev = idx; }
+        let bucket = self.bucket(self.entries[idx].device, self.entries[idx].block_id);
+        for i in self.buckets[bucket].iter_mut() {
+            if *i == moved {
+                *i = idx;
+            }
+        }
     }
 
     pub fn get_block_cache(
@@ -87,30 +283,35 @@ impl BlockCacheManager {
         block_id: usize,
         block_device: Arc<dyn BlockDevice>,
     ) -> Arc<Mutex<BlockCache>> {
-        if let Some(pair) = self.queue
-            .iter()
-            .find(|pair| pair.0 == block_id) {
-                Arc::clone(&pair.1)
-        } else {
-            // substitute
-            if self.queue.len() == BLOCK_CACHE_SIZE {
-                // from front to tail
-                if let Some((idx, _)) = self.queue
-                    .iter()
-                    .enumerate()
-                    .find(|(_, pair)| Arc::strong_count(&pair.1) == 1) {
-                    self.queue.drain(idx..=idx);
-                } else {
-                    panic!("Run out of BlockCache!");
-                }
+        let device = device_key(&block_device);
+        if let Some(idx) = self.find(device, block_id) {
+            self.detach(idx);
+            self.attach(idx);
+            return Arc::clone(&self.entries[idx].block_cache);
+        }
+        // substitute, dirty blocks stay until the journal commits them
+        while self.entries.len() >= self.capacity {
+            match self.victim() {
+                Some(idx) => self.evict(idx),
+                None => break,
             }
-            // load block into mem and push back
-            let block_cache = Arc::new(Mutex::new(
-                BlockCache::new(block_id, Arc::clone(&block_device))
-            ));
-            self.queue.push_back((block_id, Arc::clone(&block_cache)));
-            block_cache
         }
+        // load block into mem and push front
+        let block_cache = Arc::new(Mutex::new(
+            BlockCache::new(block_id, block_device, self.block_size(device))
+        ));
+        self.entries.push(CacheEntry {
+            device,
+            block_id,
+            block_cache: Arc::clone(&block_cache),
+            prev: NIL,
+            next: NIL,
+        });
+        let idx = self.entries.len() - 1;
+        let bucket = self.bucket(device, block_id);
+        self.buckets[bucket].push(idx);
+        self.attach(idx);
+        block_cache
     }
 }
 
@@ -125,4 +326,41 @@ pub fn get_block_cache(
     block_device: Arc<dyn BlockDevice>
 ) -> Arc<Mutex<BlockCache>> {
     BLOCK_CACHE_MANAGER.lock().get_block_cache(block_id, block_device)
-}
\ No newline at end of file
+}
+
+/// Load blocks of `block_device` in `block_size` bytes from now on. Must be
+/// set before any of its blocks is cached.
+pub fn set_block_size(block_device: &Arc<dyn BlockDevice>, block_size: usize) {
+    let device = device_key(block_device);
+    let mut manager = BLOCK_CACHE_MANAGER.lock();
+    manager.block_sizes.retain(|(key, _)| *key != device);
+    if block_size != BLOCK_SZ {
+        manager.block_sizes.push((device, block_size));
+    }
+}
+
+/// Let the cache keep at least `capacity` blocks. It is shared by every
+/// device, so the largest capacity asked for wins.
+pub fn reserve_block_caches(capacity: usize) {
+    let mut manager = BLOCK_CACHE_MANAGER.lock();
+    if capacity > manager.capacity {
+        manager.set_capacity(capacity);
+    }
+}
+
+/// Every block of `block_device` with changes not written back yet.
+pub fn dirty_block_caches(block_device: &Arc<dyn BlockDevice>) -> Vec<Arc<Mutex<BlockCache>>> {
+    let device = device_key(block_device);
+    let caches: Vec<Arc<Mutex<BlockCache>>> = BLOCK_CACHE_MANAGER
+        .lock()
+        .entries
+        .iter()
+        .filter(|entry| entry.device == device)
+        .map(|entry| Arc::clone(&entry.block_cache))
+        .collect();
+    // check outside of the manager lock, block caches are locked first elsewhere
+    caches
+        .into_iter()
+        .filter(|block_cache| block_cache.lock().is_dirty())
+        .collect()
+}
@@ -1,6 +1,25 @@
 use core::any::Any;
+use super::BLOCK_SZ;
 
+/// A device transferring sectors of `BLOCK_SZ` bytes.
 pub trait BlockDevice : Send + Sync + Any {
     fn read_block(&self, block_id: usize, buf: &mut [u8]);
     fn write_block(&self, block_id: usize, buf: &[u8]);
+
+    /// Read file system block `block_id`, as large as `buf`, which spans
+    /// `buf.len() / BLOCK_SZ` sectors.
+    fn read_fs_block(&self, block_id: usize, buf: &mut [u8]) {
+        let sectors = buf.len() / BLOCK_SZ;
+        for (i, sector) in buf.chunks_mut(BLOCK_SZ).enumerate() {
+            self.read_block(block_id * sectors + i, sector);
+        }
+    }
+
+    /// Write file system block `block_id`, as large as `buf`.
+    fn write_fs_block(&self, block_id: usize, buf: &[u8]) {
+        let sectors = buf.len() / BLOCK_SZ;
+        for (i, sector) in buf.chunks(BLOCK_SZ).enumerate() {
+            self.write_block(block_id * sectors + i, sector);
+        }
+    }
 }
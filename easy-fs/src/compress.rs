@@ -0,0 +1,125 @@
+use alloc::vec;
+use alloc::vec::Vec;
+
+const MIN_MATCH: usize = 4;
+const MAX_OFFSET: usize = 0xffff;
+/// Log2 of the entries of the table of positions looked up by hash.
+const HASH_LOG: usize = 12;
+
+fn read_u32(src: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes([src[pos], src[pos + 1], src[pos + 2], src[pos + 3]])
+}
+
+fn hash(seq: u32) -> usize {
+    (seq.wrapping_mul(2654435761) >> (32 - HASH_LOG)) as usize
+}
+
+fn push_length(dst: &mut Vec<u8>, mut len: usize) {
+    while len >= 255 {
+        dst.push(255);
+        len -= 255;
+    }
+    dst.push(len as u8);
+}
+
+fn push_sequence(dst: &mut Vec<u8>, literals: &[u8], found: Option<(usize, usize)>) {
+    let match_len = found.map_or(0, |(_, len)| len - MIN_MATCH);
+    dst.push((literals.len().min(15) << 4 | match_len.min(15)) as u8);
+    if literals.len() >= 15 {
+        push_length(dst, literals.len() - 15);
+    }
+    dst.extend_from_slice(literals);
+    if let Some((offset, _)) = found {
+        dst.extend_from_slice(&(offset as u16).to_le_bytes());
+        if match_len >= 15 {
+            push_length(dst, match_len - 15);
+        }
+    }
+}
+
+/// Compress `src` in the manner of LZ4, into a stream of sequences: a
+/// token, literals copied as they are and a match copied from the output so
+/// far. The high half of the token is the literal count and the low half the
+/// match length less `MIN_MATCH`, a half of 15 goes on in extra bytes that add
+/// up until one is below 255. The match offset follows the literals as two
+/// little-endian bytes. The last sequence has no match.
+///
+/// Matches are taken greedily, the first one a hash of the next four bytes
+/// leads to.
+pub fn compress(src: &[u8]) -> Vec<u8> {
+    let mut dst = Vec::with_capacity(src.len() / 2);
+    // position + 1 of the last four bytes of each hash, 0 for none
+    let mut table = vec![0usize; 1 << HASH_LOG];
+    let mut anchor = 0usize;
+    let mut pos = 0usize;
+    while pos + MIN_MATCH <= src.len() {
+        let seq = read_u32(src, pos);
+        let h = hash(seq);
+        let candidate = table[h];
+        table[h] = pos + 1;
+        if candidate == 0 || pos - (candidate - 1) > MAX_OFFSET || read_u32(src, candidate - 1) != seq {
+            pos += 1;
+            continue;
+        }
+        let candidate = candidate - 1;
+        let mut len = MIN_MATCH;
+        while pos + len < src.len() && src[candidate + len] == src[pos + len] {
+            len += 1;
+        }
+        push_sequence(&mut dst, &src[anchor..pos], Some((pos - candidate, len)));
+        pos += len;
+        anchor = pos;
+    }
+    push_sequence(&mut dst, &src[anchor..], None);
+    dst
+}
+
+fn read_length(src: &[u8], ip: &mut usize) -> Option<usize> {
+    let mut len = 0usize;
+    loop {
+        let byte = *src.get(*ip)?;
+        *ip += 1;
+        len += byte as usize;
+        if byte != 255 {
+            return Some(len);
+        }
+    }
+}
+
+/// Decompress `src` into `dst` and return the bytes it took. None if `src`
+/// is malformed or would not fit, `dst` is only partly written then.
+pub fn decompress(src: &[u8], dst: &mut [u8]) -> Option<usize> {
+    let mut ip = 0usize;
+    let mut op = 0usize;
+    while ip < src.len() {
+        let token = src[ip] as usize;
+        ip += 1;
+        let mut literal_len = token >> 4;
+        if literal_len == 15 {
+            literal_len += read_length(src, &mut ip)?;
+        }
+        let literals = src.get(ip..ip + literal_len)?;
+        dst.get_mut(op..op + literal_len)?.copy_from_slice(literals);
+        ip += literal_len;
+        op += literal_len;
+        if ip == src.len() {
+            break;
+        }
+        let offset = u16::from_le_bytes([src[ip], *src.get(ip + 1)?]) as usize;
+        ip += 2;
+        let mut match_len = token & 15;
+        if match_len == 15 {
+            match_len += read_length(src, &mut ip)?;
+        }
+        match_len += MIN_MATCH;
+        if offset == 0 || offset > op || op + match_len > dst.len() {
+            return None;
+        }
+        // byte by byte, a match may overlap what it produces
+        for i in op..op + match_len {
+            dst[i] = dst[i - offset];
+        }
+        op += match_len;
+    }
+    Some(op)
+}
@@ -0,0 +1,342 @@
+use alloc::collections::BTreeSet;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use super::{
+    BlockDevice,
+    Error,
+    Result,
+    get_block_cache,
+    dir_block_is_valid,
+    update_dir_block_checksum,
+};
+
+/// A node takes a block of words: its level, 0 for a leaf, the number of
+/// entries and then the entries, three words each. An entry is a key and,
+/// in inner nodes, the child holding the keys from that key up to the key
+/// of the next entry. The last word is left for the checksum, which nodes
+/// carry like directory blocks on images with checksums.
+type IndexNode = [u32];
+
+const HEADER_WORDS: usize = 2;
+const ENTRY_WORDS: usize = 3;
+const CHECKSUM_WORDS: usize = 1;
+/// Every node must be of a lower level than its parent and the root below
+/// this, so that a corrupt tree can neither loop nor run deep.
+const MAX_LEVELS: u32 = 16;
+
+/// Nodes reached so far by a walk down more than one path. No node has two
+/// parents, one reached twice is corrupt and would make the walk take time
+/// exponential in the depth, checksums or not.
+type Seen = BTreeSet<u32>;
+
+/// Hash of the name along with the slot of its dirent, which keeps keys
+/// unique however many names share a hash.
+pub type Key = (u32, u32);
+
+/// FNV-1a hash of a dirent name.
+pub fn name_hash(name: &str) -> u32 {
+    name.bytes().fold(0x811c9dc5u32, |hash, byte| {
+        (hash ^ byte as u32).wrapping_mul(0x01000193)
+    })
+}
+
+fn capacity(block_size: usize) -> usize {
+    (block_size / 4 - HEADER_WORDS - CHECKSUM_WORDS) / ENTRY_WORDS
+}
+
+/// Fails if the node does not match its checksum, is not of a level below
+/// `bound` or is an inner node without entries.
+fn read_node(
+    block_id: u32,
+    bound: u32,
+    block_device: &Arc<dyn BlockDevice>,
+    checksums: bool,
+) -> Result<(u32, Vec<(Key, u32)>)> {
+    let block_cache = get_block_cache(block_id as usize, Arc::clone(block_device));
+    let mut block_cache = block_cache.lock();
+    if checksums {
+        block_cache.verify(dir_block_is_valid)?;
+    }
+    let (level, entries) = block_cache.read_slice(0, |node: &IndexNode| {
+        let count = (node[1] as usize).min((node.len() - HEADER_WORDS) / ENTRY_WORDS);
+        let entries: Vec<(Key, u32)> = (0..count).map(|i| {
+            let entry = &node[HEADER_WORDS + i * ENTRY_WORDS..];
+            ((entry[0], entry[1]), entry[2])
+        }).collect();
+        (node[0], entries)
+    });
+    if level >= bound || (level > 0 && entries.is_empty()) {
+        return Err(Error::Corrupt);
+    }
+    Ok((level, entries))
+}
+
+fn write_node(
+    block_id: u32,
+    level: u32,
+    entries: &[(Key, u32)],
+    block_device: &Arc<dyn BlockDevice>,
+    checksums: bool,
+) {
+    let block_cache = get_block_cache(block_id as usize, Arc::clone(block_device));
+    let mut block_cache = block_cache.lock();
+    block_cache.modify_slice(0, |node: &mut IndexNode| {
+        node[0] = level;
+        node[1] = entries.len() as u32;
+        for (i, ((hash, slot), child)) in entries.iter().enumerate() {
+            let entry = &mut node[HEADER_WORDS + i * ENTRY_WORDS..];
+            entry[0] = *hash;
+            entry[1] = *slot;
+            entry[2] = *child;
+        }
+    });
+    if checksums {
+        block_cache.modify_slice(0, update_dir_block_checksum);
+    }
+}
+
+/// The child of an inner node `key` belongs to.
+fn child_index(entries: &[(Key, u32)], key: Key) -> usize {
+    entries.iter().rposition(|(k, _)| *k <= key).unwrap_or(0)
+}
+
+/// Visit the slots of the keys with `hash` until `f` accepts one, which
+/// is returned.
+pub fn find(
+    root: u32,
+    hash: u32,
+    block_device: &Arc<dyn BlockDevice>,
+    checksums: bool,
+    f: &mut impl FnMut(u32) -> bool,
+) -> Result<Option<u32>> {
+    if root == 0 {
+        return Ok(None);
+    }
+    find_below(root, MAX_LEVELS, hash, &mut Seen::new(), block_device, checksums, f)
+}
+
+fn find_below(
+    block_id: u32,
+    bound: u32,
+    hash: u32,
+    seen: &mut Seen,
+    block_device: &Arc<dyn BlockDevice>,
+    checksums: bool,
+    f: &mut impl FnMut(u32) -> bool,
+) -> Result<Option<u32>> {
+    if !seen.insert(block_id) {
+        return Err(Error::Corrupt);
+    }
+    let (level, entries) = read_node(block_id, bound, block_device, checksums)?;
+    if level == 0 {
+        return Ok(entries
+            .iter()
+            .filter(|((h, _), _)| *h == hash)
+            .map(|((_, slot), _)| *slot)
+            .find(|slot| f(*slot)));
+    }
+    let first = child_index(&entries, (hash, 0));
+    let last = child_index(&entries, (hash, u32::MAX));
+    for (_, child) in entries[first..=last].iter() {
+        if let Some(slot) = find_below(*child, level, hash, seen, block_device, checksums, f)? {
+            return Ok(Some(slot));
+        }
+    }
+    Ok(None)
+}
+
+/// Blocks `insert` needs for `key`: one for every node that splits on
+/// the way and one more for a new root.
+pub fn blocks_for_insert(
+    root: u32,
+    key: Key,
+    block_device: &Arc<dyn BlockDevice>,
+    block_size: usize,
+    checksums: bool,
+) -> Result<usize> {
+    if root == 0 {
+        return Ok(1);
+    }
+    let capacity = capacity(block_size);
+    // full nodes right above the leaf split along with it
+    let mut full_run = 0usize;
+    let mut all_full = true;
+    let mut block_id = root;
+    let mut bound = MAX_LEVELS;
+    loop {
+        let (level, entries) = read_node(block_id, bound, block_device, checksums)?;
+        if entries.len() < capacity {
+            full_run = 0;
+            all_full = false;
+        } else {
+            full_run += 1;
+        }
+        if level == 0 {
+            break;
+        }
+        block_id = entries[child_index(&entries, key)].1;
+        bound = level;
+    }
+    Ok(full_run + all_full as usize)
+}
+
+/// Add `key` to the tree at `root`, taking the blocks needed from
+/// `blocks`, which `blocks_for_insert` tells the number of. Only fails on
+/// a corrupt node, the tree may be left half updated then.
+pub fn insert(
+    root: &mut u32,
+    key: Key,
+    blocks: &mut Vec<u32>,
+    block_device: &Arc<dyn BlockDevice>,
+    block_size: usize,
+    checksums: bool,
+) -> Result<()> {
+    if *root == 0 {
+        *root = blocks.pop().unwrap();
+        write_node(*root, 0, &[(key, 0)], block_device, checksums);
+        return Ok(());
+    }
+    let split = insert_below(*root, MAX_LEVELS, key, blocks, block_device, block_size, checksums)?;
+    if let Some(split) = split {
+        let (level, entries) = read_node(*root, MAX_LEVELS, block_device, checksums)?;
+        let new_root = blocks.pop().unwrap();
+        write_node(new_root, level + 1, &[(entries[0].0, *root), split], block_device, checksums);
+        *root = new_root;
+    }
+    Ok(())
+}
+
+/// Insert into the subtree at `block_id`. If the node splits, the first
+/// key and the block of its upper half are returned.
+fn insert_below(
+    block_id: u32,
+    bound: u32,
+    key: Key,
+    blocks: &mut Vec<u32>,
+    block_device: &Arc<dyn BlockDevice>,
+    block_size: usize,
+    checksums: bool,
+) -> Result<Option<(Key, u32)>> {
+    let (level, mut entries) = read_node(block_id, bound, block_device, checksums)?;
+    if level == 0 {
+        let pos = entries.iter().position(|(k, _)| *k > key).unwrap_or(entries.len());
+        entries.insert(pos, (key, 0));
+    } else {
+        let i = child_index(&entries, key);
+        let lowered = key < entries[i].0;
+        if lowered {
+            entries[i].0 = key;
+        }
+        match insert_below(entries[i].1, level, key, blocks, block_device, block_size, checksums)? {
+            Some(split) => entries.insert(i + 1, split),
+            None if lowered => {}
+            None => return Ok(None),
+        }
+    }
+    if entries.len() <= capacity(block_size) {
+        write_node(block_id, level, &entries, block_device, checksums);
+        return Ok(None);
+    }
+    let upper = entries.split_off(entries.len() / 2);
+    let upper_block = blocks.pop().unwrap();
+    write_node(block_id, level, &entries, block_device, checksums);
+    write_node(upper_block, level, &upper, block_device, checksums);
+    Ok(Some((upper[0].0, upper_block)))
+}
+
+/// Remove `key`, true if it was there. Nodes left empty stay in the tree.
+pub fn remove(
+    root: u32,
+    key: Key,
+    block_device: &Arc<dyn BlockDevice>,
+    checksums: bool,
+) -> Result<bool> {
+    if root == 0 {
+        return Ok(false);
+    }
+    remove_below(root, MAX_LEVELS, key, block_device, checksums)
+}
+
+fn remove_below(
+    block_id: u32,
+    bound: u32,
+    key: Key,
+    block_device: &Arc<dyn BlockDevice>,
+    checksums: bool,
+) -> Result<bool> {
+    let (level, mut entries) = read_node(block_id, bound, block_device, checksums)?;
+    if level > 0 {
+        let child = entries[child_index(&entries, key)].1;
+        return remove_below(child, level, key, block_device, checksums);
+    }
+    match entries.iter().position(|(k, _)| *k == key) {
+        Some(pos) => {
+            entries.remove(pos);
+            write_node(block_id, level, &entries, block_device, checksums);
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Visit `root` and, as long as `visit` accepts them, the nodes below.
+/// Nothing below a corrupt node is visited, and no node twice.
+pub fn visit_nodes(
+    root: u32,
+    block_device: &Arc<dyn BlockDevice>,
+    checksums: bool,
+    visit: &mut impl FnMut(u32) -> bool,
+) {
+    visit_below(root, MAX_LEVELS, &mut Seen::new(), block_device, checksums, visit);
+}
+
+fn visit_below(
+    block_id: u32,
+    bound: u32,
+    seen: &mut Seen,
+    block_device: &Arc<dyn BlockDevice>,
+    checksums: bool,
+    visit: &mut impl FnMut(u32) -> bool,
+) {
+    if block_id == 0 || !seen.insert(block_id) || !visit(block_id) {
+        return;
+    }
+    if let Ok((level, entries)) = read_node(block_id, bound, block_device, checksums) {
+        if level > 0 {
+            for (_, child) in entries.iter() {
+                visit_below(*child, level, seen, block_device, checksums, visit);
+            }
+        }
+    }
+}
+
+/// Every key in the tree at `root`, in order.
+pub fn keys(root: u32, block_device: &Arc<dyn BlockDevice>, checksums: bool) -> Result<Vec<Key>> {
+    let mut v: Vec<Key> = Vec::new();
+    if root != 0 {
+        keys_below(root, MAX_LEVELS, &mut Seen::new(), block_device, checksums, &mut v)?;
+    }
+    Ok(v)
+}
+
+fn keys_below(
+    block_id: u32,
+    bound: u32,
+    seen: &mut Seen,
+    block_device: &Arc<dyn BlockDevice>,
+    checksums: bool,
+    v: &mut Vec<Key>,
+) -> Result<()> {
+    if !seen.insert(block_id) {
+        return Err(Error::Corrupt);
+    }
+    let (level, entries) = read_node(block_id, bound, block_device, checksums)?;
+    for (key, child) in entries.into_iter() {
+        if level == 0 {
+            v.push(key);
+        } else {
+            keys_below(child, level, seen, block_device, checksums, v)?;
+        }
+    }
+    Ok(())
+}
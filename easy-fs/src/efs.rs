@@ -4,10 +4,21 @@ use super::{
     BlockDevice,
     Bitmap,
     SuperBlock,
+    SuperBlockConfig,
     DiskInode,
     DiskInodeType,
+    DirEntry,
+    DIRENT_SZ,
+    EFS_VERSION,
     Inode,
+    Journal,
+    BlockCache,
+    Error,
+    Result,
+    MAX_BLOCK_SZ,
     get_block_cache,
+    reserve_block_caches,
+    set_block_size,
 };
 use crate::BLOCK_SZ;
 
@@ -17,115 +28,382 @@ pub struct EasyFileSystem {
     pub data_bitmap: Bitmap,
     inode_area_start_block: u32,
     data_area_start_block: u32,
+    time_source: fn() -> u64,
+    journal: Journal,
+    max_file_size: u64,
+    block_size: usize,
+    data_area_blocks: u32,
+    free_inodes: u32,
+    free_blocks: u32,
+    /// Free inodes and blocks as of the last commit, restored on abort.
+    committed_free: (u32, u32),
+    /// Whether directories get an index once they outgrow a block.
+    index_dirs: bool,
+    /// Whether new files keep small data in their inode.
+    inline_data: bool,
+    /// Whether inodes may have extended attributes.
+    xattrs: bool,
+    /// Whether metadata carries checksums.
+    checksums: bool,
+    /// Whether files may be compressed.
+    compression: bool,
+    /// Where to look for free data blocks when there is no goal, right
+    /// after the ones allocated last.
+    next_data: u32,
 }
 
-type DataBlock = [u8; BLOCK_SZ];
+/// Space usage as returned by `EasyFileSystem::stat_fs`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FsStat {
+    pub block_size: usize,
+    /// Blocks of the data area, the only ones files can take.
+    pub total_blocks: u32,
+    pub free_blocks: u32,
+    pub total_inodes: u32,
+    pub free_inodes: u32,
+}
+
+type DataBlock = [u8];
+
+/// Blocks set aside for the journal of a new image, header included.
+const JOURNAL_BLOCKS: u32 = 64;
 
 impl EasyFileSystem {
+    /// `total_blocks` and `inode_bitmap_blocks` count blocks of `block_size`
+    /// bytes, a power of two from `BLOCK_SZ` up to `MAX_BLOCK_SZ`.
+    /// `time_source` gives the current time in milliseconds for inode timestamps.
+    /// Fails with `Unsupported` for any other block size and with `NoSpace`
+    /// if the areas leave no data block for the root directory.
     pub fn create(
         block_device: Arc<dyn BlockDevice>,
         total_blocks: u32,
         inode_bitmap_blocks: u32,
-    ) -> Arc<Mutex<Self>> {
+        block_size: usize,
+        time_source: fn() -> u64,
+    ) -> Result<Arc<Mutex<Self>>> {
+        if !block_size.is_power_of_two() || !(BLOCK_SZ..=MAX_BLOCK_SZ).contains(&block_size) {
+            return Err(Error::Unsupported);
+        }
         // calculate block size of areas & create bitmaps
-        let inode_bitmap = Bitmap::new(1, inode_bitmap_blocks as usize);
+        let inode_bitmap_start = 1 + JOURNAL_BLOCKS;
+        let inode_bitmap = Bitmap::new(
+            inode_bitmap_start as usize,
+            inode_bitmap_blocks as usize,
+            block_size,
+            Some(0),
+        );
         let inode_num = inode_bitmap.maximum();
         let inode_area_blocks =
-            ((inode_num * core::mem::size_of::<DiskInode>() + BLOCK_SZ - 1) / BLOCK_SZ) as u32;
+            (inode_num * core::mem::size_of::<DiskInode>() + block_size - 1) / block_size;
+        // counted wide, the inode area alone may be larger than the device
+        let metadata_blocks =
+            inode_bitmap_start as usize + inode_bitmap_blocks as usize + inode_area_blocks;
+        if metadata_blocks >= total_blocks as usize {
+            return Err(Error::NoSpace);
+        }
+        let inode_area_blocks = inode_area_blocks as u32;
         let inode_total_blocks = inode_bitmap_blocks + inode_area_blocks;
-        let data_total_blocks = total_blocks - 1 - inode_total_blocks;
-        let data_bitmap_blocks = (data_total_blocks + 4096) / 4097;
+        let data_total_blocks = total_blocks - metadata_blocks as u32;
+        let block_bits = block_size * 8;
+        let data_bitmap_blocks =
+            ((data_total_blocks as usize + block_bits) / (block_bits + 1)) as u32;
         let data_area_blocks = data_total_blocks - data_bitmap_blocks;
+        // the root needs an inode and a block for "." and ".."
+        if inode_num == 0 || data_area_blocks == 0 {
+            return Err(Error::NoSpace);
+        }
+        set_block_size(&block_device, block_size);
         let data_bitmap = Bitmap::new(
-            (1 + inode_bitmap_blocks + inode_area_blocks) as usize,
+            (inode_bitmap_start + inode_total_blocks) as usize,
             data_bitmap_blocks as usize,
+            block_size,
+            Some(inode_bitmap_blocks as usize),
         );
         let mut efs = Self {
             block_device: Arc::clone(&block_device),
             inode_bitmap,
             data_bitmap,
-            inode_area_start_block: 1 + inode_bitmap_blocks,
-            data_area_start_block: 1 + inode_total_blocks + data_bitmap_blocks,
+            inode_area_start_block: inode_bitmap_start + inode_bitmap_blocks,
+            data_area_start_block: inode_bitmap_start + inode_total_blocks + data_bitmap_blocks,
+            time_source,
+            journal: Journal::new(1, JOURNAL_BLOCKS, true),
+            max_file_size: DiskInode::max_size(block_size),
+            block_size,
+            data_area_blocks,
+            free_inodes: inode_num as u32,
+            free_blocks: data_area_blocks,
+            committed_free: (inode_num as u32, data_area_blocks),
+            index_dirs: true,
+            inline_data: true,
+            xattrs: true,
+            checksums: true,
+            compression: true,
+            next_data: 0,
         };
-        // clear all blocks
+        // clear all blocks, written through rather than journaled
         for i in 0..total_blocks {
-            get_block_cache(
+            let block_cache = get_block_cache(
                 i as usize, 
                 Arc::clone(&block_device)
-            )
-            .lock()
-            .modify(0, |data_block: &mut DataBlock| {
+            );
+            let mut block_cache = block_cache.lock();
+            block_cache.modify_slice(0, |data_block: &mut DataBlock| {
                 for byte in data_block.iter_mut() { *byte = 0; }
             });
+            block_cache.sync();
         }
         // initialize SuperBlock
         get_block_cache(0, Arc::clone(&block_device))
         .lock()
         .modify(0, |super_block: &mut SuperBlock| {
-            super_block.initialize(
+            super_block.initialize(&SuperBlockConfig {
                 total_blocks,
                 inode_bitmap_blocks,
                 inode_area_blocks,
                 data_bitmap_blocks,
                 data_area_blocks,
-            );
+                journal_blocks: JOURNAL_BLOCKS,
+                block_size,
+                inodes: inode_num as u32,
+            });
         });
         // write back immediately
-        // create a inode for root node "/"
-        assert_eq!(efs.alloc_inode(), 0);
+        // create a inode for root node "/", the first one of a new image
+        efs.alloc_inode()?;
         let (root_inode_block_id, root_inode_offset) = efs.get_disk_inode_pos(0);
+        // both "." and ".." of the root refer to itself
+        let now = efs.now();
         get_block_cache(
             root_inode_block_id as usize,
             Arc::clone(&block_device)
         )
         .lock()
         .modify(root_inode_offset, |disk_inode: &mut DiskInode| {
-            disk_inode.initialize(DiskInodeType::Directory);
+            disk_inode.initialize(DiskInodeType::Directory, now);
+            disk_inode.increase_size((2 * DIRENT_SZ) as u64);
+            disk_inode.write_at(
+                0,
+                DirEntry::new(".", 0).as_bytes(),
+                &block_device,
+                block_size,
+                true,
+                |_| efs.alloc_data().ok(),
+            );
+            disk_inode.write_at(
+                DIRENT_SZ,
+                DirEntry::new("..", 0).as_bytes(),
+                &block_device,
+                block_size,
+                true,
+                |_| efs.alloc_data().ok(),
+            );
+            disk_inode.update_checksum();
         });
-        Arc::new(Mutex::new(efs))
+        efs.commit()?;
+        Ok(Arc::new(Mutex::new(efs)))
     }
 
-    pub fn open(block_device: Arc<dyn BlockDevice>) -> Arc<Mutex<Self>> {
-        // read SuperBlock
-        get_block_cache(0, Arc::clone(&block_device))
+    /// Replay whatever the journal holds before loading the image.
+    /// The block cache keeps at least `cache_blocks` blocks from now on.
+    pub fn open(
+        block_device: Arc<dyn BlockDevice>,
+        time_source: fn() -> u64,
+        cache_blocks: usize,
+    ) -> Result<Arc<Mutex<Self>>> {
+        reserve_block_caches(cache_blocks);
+        // the SuperBlock sits in the first sector whatever the block size is
+        let (journal_blocks, block_size, checksums) =
+            BlockCache::new(0, Arc::clone(&block_device), BLOCK_SZ)
+            .read(0, |super_block: &SuperBlock| {
+                if super_block.is_original() {
+                    return Err(Error::Unsupported);
+                }
+                if !super_block.is_valid() {
+                    return Err(Error::Corrupt);
+                }
+                // no image with this magic was made without a version
+                if super_block.version == 0 {
+                    return Err(Error::Corrupt);
+                }
+                if super_block.version > EFS_VERSION {
+                    return Err(Error::Unsupported);
+                }
+                Ok((
+                    super_block.journal_blocks,
+                    super_block.block_size(),
+                    super_block.version >= 7,
+                ))
+            })?;
+        if !block_size.is_power_of_two() || !(BLOCK_SZ..=MAX_BLOCK_SZ).contains(&block_size) {
+            return Err(Error::Corrupt);
+        }
+        set_block_size(&block_device, block_size);
+        let journal = Journal::new(1, journal_blocks, checksums);
+        journal.replay(&block_device);
+        // read SuperBlock, checked only now that the journal may have
+        // repaired a torn write of it
+        let (mut efs, counted) = get_block_cache(0, Arc::clone(&block_device))
             .lock()
             .read(0, |super_block: &SuperBlock| {
-                assert!(super_block.is_valid(), "Error loading EFS!");
+                if !super_block.checksum_is_valid() {
+                    return Err(Error::Corrupt);
+                }
+                let inode_bitmap_start = 1 + super_block.journal_blocks;
                 let inode_total_blocks =
                     super_block.inode_bitmap_blocks + super_block.inode_area_blocks;
                 let efs = Self {
                     block_device,
                     inode_bitmap: Bitmap::new(
-                        1,
-                        super_block.inode_bitmap_blocks as usize
+                        inode_bitmap_start as usize,
+                        super_block.inode_bitmap_blocks as usize,
+                        block_size,
+                        if checksums { Some(0) } else { None },
                     ),
                     data_bitmap: Bitmap::new(
-                        (1 + inode_total_blocks) as usize,
+                        (inode_bitmap_start + inode_total_blocks) as usize,
                         super_block.data_bitmap_blocks as usize,
+                        block_size,
+                        if checksums { Some(super_block.inode_bitmap_blocks as usize) } else { None },
                     ),
-                    inode_area_start_block: 1 + super_block.inode_bitmap_blocks,
-                    data_area_start_block: 1 + inode_total_blocks + super_block.data_bitmap_blocks,
+                    inode_area_start_block: inode_bitmap_start + super_block.inode_bitmap_blocks,
+                    data_area_start_block:
+                        inode_bitmap_start + inode_total_blocks + super_block.data_bitmap_blocks,
+                    time_source,
+                    journal,
+                    max_file_size: DiskInode::max_size(block_size),
+                    block_size,
+                    data_area_blocks: super_block.data_area_blocks,
+                    free_inodes: super_block.free_inodes,
+                    free_blocks: super_block.free_blocks,
+                    committed_free: (super_block.free_inodes, super_block.free_blocks),
+                    index_dirs: super_block.version >= 4,
+                    inline_data: super_block.version >= 5,
+                    xattrs: super_block.version >= 6,
+                    checksums,
+                    compression: super_block.version >= 8,
+                    next_data: 0,
                 };
-                Arc::new(Mutex::new(efs))
-            })        
+                Ok((efs, super_block.version >= 3))
+            })?;
+        // older images do not keep free counts, they are kept from now on
+        if !counted {
+            efs.free_inodes =
+                (efs.inode_bitmap.maximum() - efs.inode_bitmap.allocated(&efs.block_device)?) as u32;
+            efs.free_blocks =
+                efs.data_area_blocks - efs.data_bitmap.allocated(&efs.block_device)? as u32;
+            efs.committed_free = (efs.free_inodes, efs.free_blocks);
+        }
+        Ok(Arc::new(Mutex::new(efs)))
     }
 
     pub fn root_inode(efs: &Arc<Mutex<Self>>) -> Inode {
-        let block_device = Arc::clone(&efs.lock().block_device);
         // acquire efs lock temporarily
-        let (block_id, block_offset) = efs.lock().get_disk_inode_pos(0);
+        let (block_device, block_size, checksums, (block_id, block_offset)) = {
+            let fs = efs.lock();
+            (Arc::clone(&fs.block_device), fs.block_size, fs.checksums, fs.get_disk_inode_pos(0))
+        };
         // release efs lock
         Inode::new(
+            0,
             block_id,
             block_offset,
             Arc::clone(efs),
             block_device,
+            block_size,
+            checksums,
         )
     }
 
+    pub fn now(&self) -> u64 {
+        (self.time_source)()
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    pub fn stat_fs(&self) -> FsStat {
+        FsStat {
+            block_size: self.block_size,
+            total_blocks: self.data_area_blocks,
+            free_blocks: self.free_blocks,
+            total_inodes: self.inode_bitmap.maximum() as u32,
+            free_inodes: self.free_inodes,
+        }
+    }
+
+    /// Indexes are only added on images of version 4 on, older drivers
+    /// would not keep them up to date.
+    pub fn index_dirs(&self) -> bool {
+        self.index_dirs
+    }
+
+    /// Older drivers would take inline data for block ids.
+    pub fn inline_data(&self) -> bool {
+        self.inline_data
+    }
+
+    /// Older drivers would leak attribute blocks.
+    pub fn xattrs(&self) -> bool {
+        self.xattrs
+    }
+
+    /// Older drivers would take checksum slots of directories for free slots.
+    pub fn checksums(&self) -> bool {
+        self.checksums
+    }
+
+    /// Older drivers would read compressed clusters as they are.
+    pub fn compression(&self) -> bool {
+        self.compression
+    }
+
+    /// Largest file this image can hold, bounded by its block size.
+    pub fn max_file_size(&self) -> u64 {
+        self.max_file_size
+    }
+
+    /// Make every change since the last commit durable as one transaction.
+    /// One too large for the journal fails with NoSpace and is aborted.
+    pub fn commit(&mut self) -> Result<()> {
+        let committed = self.journal.commit(&self.block_device);
+        match committed {
+            Ok(()) => self.committed_free = (self.free_inodes, self.free_blocks),
+            Err(_) => self.abort(),
+        }
+        committed
+    }
+
+    /// Throw away every change since the last commit.
+    pub fn abort(&mut self) {
+        self.journal.abort(&self.block_device);
+        let (free_inodes, free_blocks) = self.committed_free;
+        self.free_inodes = free_inodes;
+        self.free_blocks = free_blocks;
+    }
+
+    /// End an operation: commit what it did if it succeeded, abort it if not.
+    pub fn finish<T>(&mut self, result: Result<T>) -> Result<T> {
+        match result {
+            Ok(value) => self.commit().map(|_| value),
+            Err(err) => {
+                self.abort();
+                Err(err)
+            }
+        }
+    }
+
+    /// Write back every dirty block of this file system. Operations commit
+    /// when they end, so only changes left pending are flushed here.
+    pub fn sync_all(&mut self) {
+        // a transaction too large for the journal is aborted, nothing
+        // else can be done with it here
+        let _ = self.commit();
+    }
+
     pub fn get_disk_inode_pos(&self, inode_id: u32) -> (u32, usize) {
         let inode_size = core::mem::size_of::<DiskInode>();
-        let inodes_per_block = (BLOCK_SZ / inode_size) as u32;
+        let inodes_per_block = (self.block_size / inode_size) as u32;
         let block_id = self.inode_area_start_block + inode_id / inodes_per_block;
         (block_id, (inode_id % inodes_per_block) as usize * inode_size)
     }
@@ -134,28 +412,119 @@ impl EasyFileSystem {
         self.data_area_start_block + data_block_id
     }
 
-    pub fn alloc_inode(&mut self) -> u32 {
-        self.inode_bitmap.alloc(&self.block_device).unwrap() as u32
+    /// Mirror the free counts into the SuperBlock, as part of the same
+    /// transaction as the bitmaps.
+    fn write_free_counts(&self) {
+        get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .modify(0, |super_block: &mut SuperBlock| {
+                super_block.free_inodes = self.free_inodes;
+                super_block.free_blocks = self.free_blocks;
+                super_block.update_checksum();
+            });
+    }
+
+    pub(crate) fn set_free_counts(&mut self, free_inodes: u32, free_blocks: u32) {
+        self.free_inodes = free_inodes;
+        self.free_blocks = free_blocks;
+        self.write_free_counts();
+    }
+
+    pub fn alloc_inode(&mut self) -> Result<u32> {
+        let inode_id = self.inode_bitmap.alloc(&self.block_device)?;
+        self.free_inodes -= 1;
+        self.write_free_counts();
+        Ok(inode_id as u32)
+    }
+
+    /// An inode whose bitmap block is corrupt stays allocated, one that is
+    /// free already is not counted again.
+    pub fn dealloc_inode(&mut self, inode_id: u32) {
+        if self.inode_bitmap.dealloc(&self.block_device, inode_id as usize).is_err() {
+            return;
+        }
+        self.free_inodes += 1;
+        self.write_free_counts();
     }
 
     /// Return a block ID not ID in the data area.
-    pub fn alloc_data(&mut self) -> u32 {
-        self.data_bitmap.alloc(&self.block_device).unwrap() as u32 + self.data_area_start_block
+    pub fn alloc_data(&mut self) -> Result<u32> {
+        self.alloc_data_range(1, 0).map(|(block_id, _)| block_id)
     }
 
+    /// Allocate up to `n` consecutive data blocks, starting with the first
+    /// free one at or after block `goal`, or anywhere if `goal` is 0.
+    /// Return the first block ID and how many blocks were allocated.
+    ///
+    /// Blocks are zeroed here rather than on dealloc, so that freeing
+    /// blocks only touches the bitmap within a transaction.
+    pub fn alloc_data_range(&mut self, n: usize, goal: u32) -> Result<(u32, u32)> {
+        let hint = if goal >= self.data_area_start_block {
+            goal - self.data_area_start_block
+        } else {
+            self.next_data
+        };
+        let mut run = self.data_bitmap
+            .alloc_range(&self.block_device, n, hint as usize)?;
+        // the last bitmap block covers more than the data area, search
+        // again from the start before giving up
+        if run.0 >= self.data_area_blocks as usize && hint > 0 {
+            self.dealloc_bits(run);
+            run = self.data_bitmap
+                .alloc_range(&self.block_device, n, 0)?;
+        }
+        let area = self.data_area_blocks as usize;
+        if run.0 + run.1 > area {
+            self.dealloc_bits((area.max(run.0), run.0 + run.1 - area.max(run.0)));
+            if run.0 >= area {
+                return Err(Error::NoSpace);
+            }
+            run.1 = area - run.0;
+        }
+        let (bit, len) = (run.0 as u32, run.1 as u32);
+        self.next_data = (bit + len) % self.data_area_blocks;
+        self.free_blocks -= len;
+        self.write_free_counts();
+        let block_id = bit + self.data_area_start_block;
+        for id in block_id..block_id + len {
+            get_block_cache(
+                id as usize,
+                Arc::clone(&self.block_device)
+            )
+            .lock()
+            .modify_slice(0, |data_block: &mut DataBlock| {
+                data_block.iter_mut().for_each(|p| { *p = 0; })
+            });
+        }
+        Ok((block_id, len))
+    }
+
+    /// Clear bits `run.0..run.0 + run.1` of the data bitmap, which were
+    /// never counted as allocated.
+    fn dealloc_bits(&self, run: (usize, usize)) {
+        for bit in run.0..run.0 + run.1 {
+            // the bitmap block passed its checks when the bits were set
+            let _ = self.data_bitmap.dealloc(&self.block_device, bit);
+        }
+    }
+
+    /// A block whose bitmap block is corrupt stays allocated, one that is
+    /// free already is not counted again. Block ids outside of the data
+    /// area, as a corrupt indirect block may hold, are ignored.
     pub fn dealloc_data(&mut self, block_id: u32) {
-        get_block_cache(
-            block_id as usize,
-            Arc::clone(&self.block_device)
-        )
-        .lock()
-        .modify(0, |data_block: &mut DataBlock| {
-            data_block.iter_mut().for_each(|p| { *p = 0; })
-        });
-        self.data_bitmap.dealloc(
+        if block_id < self.data_area_start_block
+            || block_id - self.data_area_start_block >= self.data_area_blocks
+        {
+            return;
+        }
+        if self.data_bitmap.dealloc(
             &self.block_device,
             (block_id - self.data_area_start_block) as usize
-        )
+        ).is_err() {
+            return;
+        }
+        self.free_blocks += 1;
+        self.write_free_counts();
     }
 
 }
\ No newline at end of file
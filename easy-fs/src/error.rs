@@ -0,0 +1,35 @@
+/// Errors easy-fs returns instead of panicking.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// No free inode or data block left.
+    NoSpace,
+    /// No dirent of that name.
+    NotFound,
+    /// A directory operation on an inode that is not a directory.
+    NotDir,
+    /// A file operation on an inode that is not a regular file.
+    NotFile,
+    /// A directory where the operation takes anything else.
+    IsDir,
+    /// Removing or replacing a directory that still has entries.
+    NotEmpty,
+    /// The name is taken already.
+    Exists,
+    /// The inode has as many links as its link count can hold.
+    TooManyLinks,
+    /// The image is not easy-fs or is inconsistent.
+    Corrupt,
+    /// The name does not fit a dirent.
+    NameTooLong,
+    /// The name is empty or contains a path separator, or the operation
+    /// cannot take it, like "." or a directory below the one moved.
+    InvalidName,
+    /// Past the largest file size the image supports.
+    FileTooLarge,
+    /// The image uses a format version newer than this easy-fs, or one
+    /// too old for the operation, or the inode cannot take it, or the
+    /// inodes belong to different file systems.
+    Unsupported,
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
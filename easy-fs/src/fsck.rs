@@ -0,0 +1,245 @@
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+use super::{
+    DiskInode,
+    EasyFileSystem,
+    Inode,
+    SuperBlock,
+    Result,
+    get_block_cache,
+};
+
+/// Inconsistencies found by `EasyFileSystem::fsck`.
+#[derive(Debug, PartialEq)]
+pub enum FsckProblem {
+    /// A dirent of directory `dir` refers to an inode id past the inode area.
+    InodeOutOfRange { dir: u32, inode_id: u32 },
+    /// A reachable inode is free in the inode bitmap.
+    FreeInodeInUse(u32),
+    /// An inode is allocated but no dirent leads to it.
+    LeakedInode(u32),
+    /// `nlink` of an inode differs from the dirents referring to it.
+    WrongLinkCount { inode_id: u32, nlink: u16, links: u32 },
+    /// "." or ".." of a directory do not refer to itself and its parent.
+    BadDotEntries(u32),
+    /// The dirent index of a directory disagrees with its dirents.
+    BadDirIndex(u32),
+    /// A block id outside of the data area.
+    BlockOutOfRange { inode_id: u32, block_id: u32 },
+    /// A block referred to more than once, by this inode or another one.
+    DuplicateBlock { inode_id: u32, block_id: u32 },
+    /// A referenced block is free in the data bitmap.
+    FreeBlockInUse { inode_id: u32, block_id: u32 },
+    /// A block is allocated but not referred to by any inode.
+    LeakedBlock(u32),
+    /// The free counts kept differ from the bitmaps, which give these.
+    WrongFreeCounts { free_inodes: u32, free_blocks: u32 },
+    /// The block of an inode or one of its directory blocks fails its checksum.
+    CorruptInode(u32),
+    /// A bitmap block fails its checksum.
+    CorruptBitmap(u32),
+}
+
+/// Report the bitmap block of a failed lookup once.
+fn check_bitmap(
+    allocated: Result<bool>,
+    block_id: usize,
+    problems: &mut Vec<FsckProblem>,
+) -> bool {
+    allocated.unwrap_or_else(|_| {
+        let problem = FsckProblem::CorruptBitmap(block_id as u32);
+        if !problems.contains(&problem) {
+            problems.push(problem);
+        }
+        // taken as allocated, nothing gets freed on its behalf
+        true
+    })
+}
+
+impl EasyFileSystem {
+    /// Walk every inode reachable from the root and cross-check the
+    /// bitmaps against what is actually referenced. With `repair`,
+    /// leaked inodes and blocks are given back to their bitmaps, the
+    /// problems returned are the ones found before repairing. Nothing is
+    /// repaired on an image with corrupt metadata, where whatever lies
+    /// behind it would pass for leaked.
+    pub fn fsck(efs: &Arc<Mutex<Self>>, repair: bool) -> Vec<FsckProblem> {
+        let (block_device, inode_count, data_start, data_blocks, block_size, checksums) = {
+            let fs = efs.lock();
+            let data_blocks = get_block_cache(0, Arc::clone(&fs.block_device))
+                .lock()
+                .read(0, |super_block: &SuperBlock| super_block.data_area_blocks);
+            (
+                Arc::clone(&fs.block_device),
+                fs.inode_bitmap.maximum(),
+                fs.get_data_block_id(0),
+                data_blocks as usize,
+                fs.block_size(),
+                fs.checksums(),
+            )
+        };
+        let mut problems: Vec<FsckProblem> = Vec::new();
+        // counted wider than nlink, corrupt dirents may refer to an inode
+        // more often than nlink can hold
+        let mut links = vec![0u32; inode_count];
+        // None for inodes that could not be read
+        let mut nlinks: Vec<Option<u16>> = vec![None; inode_count];
+        let mut reached = vec![false; inode_count];
+        let mut used = vec![false; data_blocks];
+        // the root has no dirent but keeps a link of its own
+        links[0] = 1;
+        reached[0] = true;
+        let mut stack: Vec<(u32, u32)> = vec![(0, 0)];
+        while let Some((inode_id, parent_id)) = stack.pop() {
+            // blocks out of range must not be read
+            let mut readable = true;
+            let is_dir = {
+                let fs = efs.lock();
+                let allocated = fs.inode_bitmap.is_allocated(&block_device, inode_id as usize);
+                let bitmap_block = fs.inode_bitmap.block_id(inode_id as usize);
+                if !check_bitmap(allocated, bitmap_block, &mut problems) {
+                    problems.push(FsckProblem::FreeInodeInUse(inode_id));
+                }
+                let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
+                let block_cache = get_block_cache(block_id as usize, Arc::clone(&block_device));
+                let mut block_cache = block_cache.lock();
+                if checksums && block_cache.verify(DiskInode::block_is_valid).is_err() {
+                    problems.push(FsckProblem::CorruptInode(inode_id));
+                    continue;
+                }
+                block_cache.read(block_offset, |disk_inode: &DiskInode| {
+                    nlinks[inode_id as usize] = Some(disk_inode.nlink);
+                    disk_inode.visit_blocks(&block_device, block_size, checksums, |id| {
+                        if id < data_start || (id - data_start) as usize >= data_blocks {
+                            problems.push(FsckProblem::BlockOutOfRange { inode_id, block_id: id });
+                            readable = false;
+                            return false;
+                        }
+                        let idx = (id - data_start) as usize;
+                        if used[idx] {
+                            problems.push(FsckProblem::DuplicateBlock { inode_id, block_id: id });
+                        }
+                        used[idx] = true;
+                        let allocated = fs.data_bitmap.is_allocated(&block_device, idx);
+                        if !check_bitmap(allocated, fs.data_bitmap.block_id(idx), &mut problems) {
+                            problems.push(FsckProblem::FreeBlockInUse { inode_id, block_id: id });
+                        }
+                        true
+                    });
+                    disk_inode.is_dir()
+                })
+            };
+            if !is_dir || !readable {
+                continue;
+            }
+            let (dir_block_id, dir_block_offset) = efs.lock().get_disk_inode_pos(inode_id);
+            let dir = Inode::new(
+                inode_id,
+                dir_block_id,
+                dir_block_offset,
+                Arc::clone(efs),
+                Arc::clone(&block_device),
+                block_size,
+                checksums,
+            );
+            let dirents = match dir.dirents() {
+                Ok(dirents) => dirents,
+                Err(_) => {
+                    problems.push(FsckProblem::CorruptInode(inode_id));
+                    continue;
+                }
+            };
+            let mut dots = (false, false);
+            for (name, child_id) in dirents {
+                if name == "." {
+                    dots.0 = child_id == inode_id;
+                    continue;
+                }
+                if name == ".." {
+                    dots.1 = child_id == parent_id;
+                    continue;
+                }
+                if child_id as usize >= inode_count {
+                    problems.push(FsckProblem::InodeOutOfRange { dir: inode_id, inode_id: child_id });
+                    continue;
+                }
+                links[child_id as usize] = links[child_id as usize].saturating_add(1);
+                if !reached[child_id as usize] {
+                    reached[child_id as usize] = true;
+                    stack.push((child_id, inode_id));
+                }
+            }
+            if dots != (true, true) {
+                problems.push(FsckProblem::BadDotEntries(inode_id));
+            }
+            if !dir.dir_index_is_valid() {
+                problems.push(FsckProblem::BadDirIndex(inode_id));
+            }
+        }
+        let repair = repair && !problems.iter().any(|problem| {
+            matches!(problem, FsckProblem::CorruptInode(_) | FsckProblem::CorruptBitmap(_))
+        });
+        let mut fs = efs.lock();
+        for inode_id in 0..inode_count {
+            if reached[inode_id] {
+                match nlinks[inode_id] {
+                    Some(nlink) if nlink as u32 != links[inode_id] => {
+                        problems.push(FsckProblem::WrongLinkCount {
+                            inode_id: inode_id as u32,
+                            nlink,
+                            links: links[inode_id],
+                        });
+                    }
+                    _ => {}
+                }
+            } else if check_bitmap(
+                fs.inode_bitmap.is_allocated(&block_device, inode_id),
+                fs.inode_bitmap.block_id(inode_id),
+                &mut problems,
+            ) {
+                problems.push(FsckProblem::LeakedInode(inode_id as u32));
+                if repair {
+                    fs.dealloc_inode(inode_id as u32);
+                }
+            }
+        }
+        for (idx, used) in used.iter().enumerate() {
+            if !used && check_bitmap(
+                fs.data_bitmap.is_allocated(&block_device, idx),
+                fs.data_bitmap.block_id(idx),
+                &mut problems,
+            ) {
+                let block_id = fs.get_data_block_id(idx as u32);
+                problems.push(FsckProblem::LeakedBlock(block_id));
+                if repair {
+                    fs.dealloc_data(block_id);
+                }
+            }
+        }
+        // the bitmaps cannot be counted with a corrupt block, which was
+        // reported above
+        let allocated = (
+            fs.inode_bitmap.allocated(&block_device),
+            fs.data_bitmap.allocated(&block_device),
+        );
+        if let (Ok(inodes_allocated), Ok(blocks_allocated)) = allocated {
+            let free_inodes = (inode_count - inodes_allocated) as u32;
+            let free_blocks = (data_blocks - blocks_allocated) as u32;
+            let stat = fs.stat_fs();
+            if (stat.free_inodes, stat.free_blocks) != (free_inodes, free_blocks) {
+                problems.push(FsckProblem::WrongFreeCounts { free_inodes, free_blocks });
+                if repair {
+                    fs.set_free_counts(free_inodes, free_blocks);
+                }
+            }
+        }
+        if repair {
+            // repairs too many for the journal are dropped, to be found
+            // again by the next run
+            let _ = fs.commit();
+        }
+        problems
+    }
+}
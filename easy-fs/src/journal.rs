@@ -0,0 +1,185 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use super::{
+    BLOCK_SZ,
+    BlockDevice,
+    Error,
+    Result,
+    get_block_cache,
+    dirty_block_caches,
+    crc32_update,
+};
+
+const JOURNAL_MAGIC: u32 = 0x6a726e6c;
+/// How many blocks one transaction may log, bounded by the header, which
+/// fits the smallest block size.
+const JOURNAL_LOG_LIMIT: usize = BLOCK_SZ / 4 - 3;
+
+type DataBlock = [u8];
+
+/// First block of the journal. A non-zero `count` with a valid magic
+/// means the transaction is committed but maybe not written home yet.
+#[repr(C)]
+struct JournalHeader {
+    magic: u32,
+    count: u32,
+    block_ids: [u32; JOURNAL_LOG_LIMIT],
+    /// Checksum of the fields above and of the blocks logged, on images
+    /// with checksums. A header that fails it was torn or rotted.
+    checksum: u32,
+}
+
+impl JournalHeader {
+    /// `log_crc` is the running checksum of the blocks logged.
+    fn checksum(&self, log_crc: u32) -> u32 {
+        let header = unsafe {
+            core::slice::from_raw_parts(
+                self as *const _ as *const u8,
+                core::mem::size_of::<Self>() - 4,
+            )
+        };
+        !crc32_update(log_crc, header)
+    }
+}
+
+/// Redo log kept in the blocks right after the SuperBlock: a header
+/// followed by copies of the blocks of the last transaction.
+///
+/// Every block modified through the block cache belongs to the open
+/// transaction until `commit`, since the cache never writes back dirty
+/// blocks before that.
+pub struct Journal {
+    start_block: u32,
+    blocks: u32,
+    checksums: bool,
+}
+
+impl Journal {
+    pub fn new(start_block: u32, blocks: u32, checksums: bool) -> Self {
+        Self {
+            start_block,
+            blocks,
+            checksums,
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        (self.blocks as usize - 1).min(JOURNAL_LOG_LIMIT)
+    }
+
+    fn write_header(&self, block_ids: &[u32], log_crc: u32, block_device: &Arc<dyn BlockDevice>) {
+        let header_cache = get_block_cache(self.start_block as usize, Arc::clone(block_device));
+        let mut header_cache = header_cache.lock();
+        header_cache.modify(0, |header: &mut JournalHeader| {
+            header.magic = JOURNAL_MAGIC;
+            header.count = block_ids.len() as u32;
+            header.block_ids[..block_ids.len()].copy_from_slice(block_ids);
+            header.checksum = header.checksum(log_crc);
+        });
+        header_cache.sync();
+    }
+
+    /// Log every dirty block of `block_device`, mark the transaction as
+    /// committed and then write the blocks back to where they belong.
+    ///
+    /// A transaction larger than the journal fails with NoSpace and is
+    /// left dirty, for the caller to abort.
+    pub fn commit(&self, block_device: &Arc<dyn BlockDevice>) -> Result<()> {
+        let dirty = dirty_block_caches(block_device);
+        if dirty.is_empty() {
+            return Ok(());
+        }
+        // images without a journal get no crash consistency
+        if self.blocks == 0 {
+            for block_cache in dirty.iter() {
+                block_cache.lock().sync();
+            }
+            return Ok(());
+        }
+        if dirty.len() > self.capacity() {
+            return Err(Error::NoSpace);
+        }
+        // copy blocks into the log
+        let mut block_ids: Vec<u32> = Vec::new();
+        let mut log_crc = !0;
+        for (i, block_cache) in dirty.iter().enumerate() {
+            let block_cache = block_cache.lock();
+            block_ids.push(block_cache.block_id() as u32);
+            let log_cache = get_block_cache(
+                self.start_block as usize + 1 + i,
+                Arc::clone(block_device),
+            );
+            let mut log_cache = log_cache.lock();
+            block_cache.read_slice(0, |data_block: &DataBlock| {
+                log_crc = crc32_update(log_crc, data_block);
+                log_cache.modify_slice(0, |log_block: &mut DataBlock| {
+                    log_block.copy_from_slice(data_block);
+                });
+            });
+            log_cache.sync();
+        }
+        // commit point
+        self.write_header(&block_ids, log_crc, block_device);
+        // checkpoint
+        for block_cache in dirty.iter() {
+            block_cache.lock().sync();
+        }
+        self.write_header(&[], !0, block_device);
+        Ok(())
+    }
+
+    /// Throw away the open transaction: every dirty block is loaded again
+    /// from disk.
+    pub fn abort(&self, block_device: &Arc<dyn BlockDevice>) {
+        for block_cache in dirty_block_caches(block_device) {
+            block_cache.lock().discard();
+        }
+    }
+
+    /// Write back a transaction that was committed but not checkpointed.
+    pub fn replay(&self, block_device: &Arc<dyn BlockDevice>) {
+        if self.blocks == 0 {
+            return;
+        }
+        let header_cache = get_block_cache(self.start_block as usize, Arc::clone(block_device));
+        let block_ids: Vec<u32> = header_cache.lock().read(0, |header: &JournalHeader| {
+            if header.magic == JOURNAL_MAGIC && header.count as usize <= self.capacity() {
+                header.block_ids[..header.count as usize].to_vec()
+            } else {
+                Vec::new()
+            }
+        });
+        if block_ids.is_empty() {
+            return;
+        }
+        let mut log: Vec<Vec<u8>> = Vec::new();
+        let mut log_crc = !0;
+        for i in 0..block_ids.len() {
+            let data = get_block_cache(
+                self.start_block as usize + 1 + i,
+                Arc::clone(block_device),
+            )
+            .lock()
+            .read_slice(0, |log_block: &DataBlock| log_block.to_vec());
+            log_crc = crc32_update(log_crc, &data);
+            log.push(data);
+        }
+        // a transaction that fails its checksum never reached its commit point
+        let torn = header_cache.lock().read(0, |header: &JournalHeader| {
+            header.checksum != header.checksum(log_crc)
+        });
+        if self.checksums && torn {
+            self.write_header(&[], !0, block_device);
+            return;
+        }
+        for (block_id, data) in block_ids.iter().zip(log.iter()) {
+            let home_cache = get_block_cache(*block_id as usize, Arc::clone(block_device));
+            let mut home_cache = home_cache.lock();
+            home_cache.modify_slice(0, |data_block: &mut DataBlock| {
+                data_block.copy_from_slice(data);
+            });
+            home_cache.sync();
+        }
+        self.write_header(&[], !0, block_device);
+    }
+}
@@ -1,21 +1,80 @@
-use core::fmt::{Debug, Formatter, Result};
+use core::fmt::{self, Debug, Formatter};
 use super::{
     BLOCK_SZ,
     BlockDevice,
+    Error,
+    Result,
     get_block_cache,
+    crc32,
+    dir_index,
+    compress,
+    decompress,
 };
 use alloc::sync::Arc;
+use alloc::vec;
 use alloc::vec::Vec;
 
-const EFS_MAGIC: u32 = 0x3b800001;
+/// Changed along with the size of `DiskInode`, which is 128 bytes on
+/// images of the original layout.
+const EFS_MAGIC: u32 = 0x3b800002;
+/// Magic of images of the original layout, which cannot be opened.
+const ORIGINAL_EFS_MAGIC: u32 = 0x3b800001;
+pub const MODE_MASK: u16 = 0o777;
 const INODE_DIRECT_COUNT: usize = 28;
-const NAME_LENGTH_LIMIT: usize = 27;
-const INODE_INDIRECT1_COUNT: usize = BLOCK_SZ / 4;
-const INODE_INDIRECT2_COUNT: usize = INODE_INDIRECT1_COUNT * INODE_INDIRECT1_COUNT;
 const DIRECT_BOUND: usize = INODE_DIRECT_COUNT;
-const INDIRECT1_BOUND: usize = DIRECT_BOUND + INODE_INDIRECT1_COUNT;
-#[allow(unused)]
-const INDIRECT2_BOUND: usize = INDIRECT1_BOUND + INODE_INDIRECT2_COUNT;
+/// Largest block size, `BLOCK_SZ` being the smallest.
+pub const MAX_BLOCK_SZ: usize = 4096;
+/// On-disk format version. Images made before the SuperBlock recorded a
+/// version have the magic of the original layout and do not open, see
+/// `EFS_MAGIC`.
+/// Version 1 has indirect3 and 64-bit sizes.
+/// Version 2 records the block size, older images use `BLOCK_SZ`.
+/// Version 3 keeps free counts, they are counted at open on older images.
+/// Version 4 indexes directories larger than a block, directories of older
+/// images are scanned.
+/// Version 5 keeps the data of small files in their inode.
+/// Version 6 adds extended attributes.
+/// Version 7 adds checksums of the SuperBlock, bitmaps, inodes, directory
+/// blocks, dirent index nodes and the journal.
+/// Version 8 compresses the files that ask for it.
+pub const EFS_VERSION: u32 = 8;
+/// Bitmap blocks whose checksums the SuperBlock keeps, the ones past them go
+/// unchecked. It takes an image of over 200MiB with 512-byte blocks to have more.
+pub const BITMAP_CHECKSUMS: usize = BLOCK_SZ / 4 - 12;
+/// Bytes of data an inode holds itself, in place of its direct blocks.
+const INLINE_CAPACITY: usize = INODE_DIRECT_COUNT * 4;
+/// `DiskInode::flags`: data is kept inline.
+const INLINE_DATA: u32 = 1;
+/// `DiskInode::flags`: data is compressed cluster by cluster.
+const COMPRESSED: u32 = 2;
+/// Data blocks of a cluster, the unit data is compressed in.
+pub const CLUSTER_BLOCKS: usize = 8;
+/// A compressed cluster starts with the length of its compressed data and
+/// the length of the data, both u32.
+const CLUSTER_HEADER_SZ: usize = 8;
+/// Where `DiskInode` keeps its checksum, which covers the bytes before it.
+const INODE_CHECKSUM_OFFSET: usize = 252;
+
+/// Depth, first data block and data block count of the index trees
+/// behind indirect1, indirect2 and indirect3.
+fn index_trees(block_size: usize) -> [(usize, usize, usize); 3] {
+    let indirect1_count = block_size / 4;
+    let indirect2_count = indirect1_count * indirect1_count;
+    let indirect3_count = indirect2_count * indirect1_count;
+    let indirect1_bound = DIRECT_BOUND + indirect1_count;
+    let indirect2_bound = indirect1_bound + indirect2_count;
+    [
+        (1, DIRECT_BOUND, indirect1_count),
+        (2, indirect1_bound, indirect2_count),
+        (3, indirect2_bound, indirect3_count),
+    ]
+}
+
+/// Data blocks reachable through the index trees up to `depth`.
+fn data_block_bound(depth: usize, block_size: usize) -> usize {
+    let (_, first, count) = index_trees(block_size)[depth - 1];
+    first + count
+}
 
 #[repr(C)]
 pub struct SuperBlock {
@@ -25,334 +84,539 @@ pub struct SuperBlock {
     pub inode_area_blocks: u32,
     pub data_bitmap_blocks: u32,
     pub data_area_blocks: u32,
+    /// Journal blocks right after the SuperBlock, 0 on images made without one.
+    pub journal_blocks: u32,
+    /// Format version, see `EFS_VERSION`.
+    pub version: u32,
+    /// 0 on images older than version 2.
+    block_size: u32,
+    /// Free inodes and data blocks, only kept up to date since version 3.
+    pub free_inodes: u32,
+    pub free_blocks: u32,
+    /// Checksums of the inode bitmap blocks and then of the data bitmap
+    /// blocks, since version 7.
+    bitmap_checksums: [u32; BITMAP_CHECKSUMS],
+    /// Checksum of the fields above, since version 7. The SuperBlock fills
+    /// the first sector with it.
+    checksum: u32,
 }
 
 impl Debug for SuperBlock {
-    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         f.debug_struct("SuperBlock")
             .field("total_blocks", &self.total_blocks)
             .field("inode_bitmap_blocks", &self.inode_bitmap_blocks)
             .field("inode_area_blocks", &self.inode_area_blocks)
             .field("data_bitmap_blocks", &self.data_bitmap_blocks)
             .field("data_area_blocks", &self.data_area_blocks)
+            .field("journal_blocks", &self.journal_blocks)
+            .field("version", &self.version)
+            .field("block_size", &self.block_size())
+            .field("free_inodes", &self.free_inodes)
+            .field("free_blocks", &self.free_blocks)
             .finish()
     }
 }
 
+/// How a new image is laid out, areas in blocks of `block_size`.
+pub struct SuperBlockConfig {
+    pub total_blocks: u32,
+    pub inode_bitmap_blocks: u32,
+    pub inode_area_blocks: u32,
+    pub data_bitmap_blocks: u32,
+    pub data_area_blocks: u32,
+    pub journal_blocks: u32,
+    pub block_size: usize,
+    /// Inodes the inode bitmap can hand out, all free.
+    pub inodes: u32,
+}
+
 impl SuperBlock {
-    pub fn initialize(
-        &mut self,
-        total_blocks: u32,
-        inode_bitmap_blocks: u32,
-        inode_area_blocks: u32,
-        data_bitmap_blocks: u32,
-        data_area_blocks: u32,
-    ) {
+    pub fn initialize(&mut self, config: &SuperBlockConfig) {
+        let bitmap_checksum = crc32(&vec![0u8; config.block_size]);
         *self = Self {
             magic: EFS_MAGIC,
-            total_blocks,
-            inode_bitmap_blocks,
-            inode_area_blocks,
-            data_bitmap_blocks,
-            data_area_blocks,
-        }
+            total_blocks: config.total_blocks,
+            inode_bitmap_blocks: config.inode_bitmap_blocks,
+            inode_area_blocks: config.inode_area_blocks,
+            data_bitmap_blocks: config.data_bitmap_blocks,
+            data_area_blocks: config.data_area_blocks,
+            journal_blocks: config.journal_blocks,
+            version: EFS_VERSION,
+            block_size: config.block_size as u32,
+            free_inodes: config.inodes,
+            free_blocks: config.data_area_blocks,
+            // bitmaps start out zeroed
+            bitmap_checksums: [bitmap_checksum; BITMAP_CHECKSUMS],
+            checksum: 0,
+        };
+        self.update_checksum();
     }
     pub fn is_valid(&self) -> bool {
         self.magic == EFS_MAGIC
     }
+    pub fn is_original(&self) -> bool {
+        self.magic == ORIGINAL_EFS_MAGIC
+    }
+    /// Images older than checksums always pass.
+    pub fn checksum_is_valid(&self) -> bool {
+        self.version < 7 || self.checksum == self.compute_checksum()
+    }
+    fn compute_checksum(&self) -> u32 {
+        let bytes = unsafe {
+            core::slice::from_raw_parts(
+                self as *const _ as *const u8,
+                core::mem::size_of::<Self>() - 4,
+            )
+        };
+        crc32(bytes)
+    }
+    /// Bring the checksum up to date, after every change to the SuperBlock.
+    pub fn update_checksum(&mut self) {
+        self.checksum = self.compute_checksum();
+    }
+    /// Checksum of bitmap block `idx`, counting the inode bitmap blocks
+    /// first. None for the blocks past the ones kept.
+    pub fn bitmap_checksum(&self, idx: usize) -> Option<u32> {
+        self.bitmap_checksums.get(idx).copied()
+    }
+    pub fn set_bitmap_checksum(&mut self, idx: usize, checksum: u32) {
+        if idx < BITMAP_CHECKSUMS {
+            self.bitmap_checksums[idx] = checksum;
+            self.update_checksum();
+        }
+    }
+    pub fn block_size(&self) -> usize {
+        if self.block_size == 0 { BLOCK_SZ } else { self.block_size as usize }
+    }
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum DiskInodeType {
     File,
     Directory,
+    /// Data of a symbolic link is the path it points to.
+    SymLink,
 }
 
-type IndirectBlock = [u32; BLOCK_SZ / 4];
-type DataBlock = [u8; BLOCK_SZ];
+type IndirectBlock = [u32];
+type DataBlock = [u8];
 
 #[repr(C)]
 pub struct DiskInode {
-    pub size: u32,
+    /// Low half of the size.
+    size_lo: u32,
     pub direct: [u32; INODE_DIRECT_COUNT],
     pub indirect1: u32,
     pub indirect2: u32,
     type_: DiskInodeType,
+    /// Number of dirents referring to this inode, "." and ".." excluded.
+    pub nlink: u16,
+    /// Last access, last modification and creation time, in milliseconds
+    /// of whatever clock the filesystem was given.
+    pub atime: u64,
+    pub mtime: u64,
+    pub ctime: u64,
+    /// Owner and group ids.
+    pub uid: u32,
+    pub gid: u32,
+    /// Permission bits, `0o777` at most.
+    pub mode: u16,
+    /// High half of the size and the triple indirect block.
+    size_hi: u32,
+    pub indirect3: u32,
+    /// Root of the dirent index of a directory, 0 while it has none, and
+    /// the first slot that may be free. Only kept along with the index.
+    pub dir_index: u32,
+    pub dir_free_slot: u32,
+    flags: u32,
+    /// Block of extended attributes, 0 while there are none.
+    pub xattr_block: u32,
+    /// Room for more fields, keeps DiskInode 256 bytes large.
+    _reserved: [u8; 64],
+    /// Checksum of the fields above on images with checksums.
+    checksum: u32,
 }
 
 impl DiskInode {
-    /// indirect1 and indirect2 block are allocated only when they are needed.
-    pub fn initialize(&mut self, type_: DiskInodeType) {
-        self.size = 0;
+    /// indirect1, indirect2 and indirect3 block are allocated only when they are needed.
+    pub fn initialize(&mut self, type_: DiskInodeType, now: u64) {
+        self.set_size(0);
         self.direct.iter_mut().for_each(|v| *v = 0);
         self.indirect1 = 0;
         self.indirect2 = 0;
+        self.indirect3 = 0;
+        self.dir_index = 0;
+        self.dir_free_slot = 0;
+        self.flags = 0;
+        self.xattr_block = 0;
         self.type_ = type_;
+        self.nlink = 1;
+        self.atime = now;
+        self.mtime = now;
+        self.ctime = now;
+        self.uid = 0;
+        self.gid = 0;
+        self.mode = match type_ {
+            DiskInodeType::File => 0o644,
+            DiskInodeType::Directory => 0o755,
+            DiskInodeType::SymLink => 0o777,
+        };
+        self._reserved.iter_mut().for_each(|v| *v = 0);
+        self.checksum = 0;
+    }
+    fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            core::slice::from_raw_parts(
+                self as *const _ as *const u8,
+                core::mem::size_of::<Self>(),
+            )
+        }
+    }
+    /// Bring the checksum up to date, after every change on images with checksums.
+    pub fn update_checksum(&mut self) {
+        self.checksum = crc32(&self.as_bytes()[..INODE_CHECKSUM_OFFSET]);
+    }
+    /// Whether the checksum of every inode of an inode block matches.
+    /// Inodes never written are all zeros and pass as well.
+    pub fn block_is_valid(block: &DataBlock) -> bool {
+        block.chunks(core::mem::size_of::<Self>()).all(|inode| {
+            let (data, checksum) = inode.split_at(INODE_CHECKSUM_OFFSET);
+            let checksum = u32::from_le_bytes([checksum[0], checksum[1], checksum[2], checksum[3]]);
+            checksum == crc32(data) || inode.iter().all(|byte| *byte == 0)
+        })
+    }
+    pub fn type_(&self) -> DiskInodeType {
+        self.type_
     }
     pub fn is_dir(&self) -> bool {
         self.type_ == DiskInodeType::Directory
     }
-    #[allow(unused)]
     pub fn is_file(&self) -> bool {
         self.type_ == DiskInodeType::File
     }
-    /// Return block number correspond to size.
-    pub fn data_blocks(&self) -> u32 {
-        Self::_data_blocks(self.size)
+    pub fn is_symlink(&self) -> bool {
+        self.type_ == DiskInodeType::SymLink
     }
-    fn _data_blocks(size: u32) -> u32 {
-        (size + BLOCK_SZ as u32 - 1) / BLOCK_SZ as u32
+    pub fn is_inline(&self) -> bool {
+        self.flags & INLINE_DATA != 0
     }
-    /// Return number of blocks needed include indirect1/2.
-    pub fn total_blocks(size: u32) -> u32 {
-        let data_blocks = Self::_data_blocks(size) as usize;
-        let mut total = data_blocks as usize;
-        // indirect1
-        if data_blocks > INODE_DIRECT_COUNT {
-            total += 1;
-        }
-        // indirect2
-        if data_blocks > INDIRECT1_BOUND {
-            total += 1;
-            // sub indirect1
-            total += (data_blocks - INDIRECT1_BOUND + INODE_INDIRECT1_COUNT - 1) / INODE_INDIRECT1_COUNT;
+    /// Keep the data of this empty inode in place of its direct blocks,
+    /// until it is written past them. Bytes beyond are a hole till then.
+    pub fn set_inline(&mut self) {
+        assert!(self.size() == 0 && !self.is_dir());
+        self.direct.iter_mut().for_each(|v| *v = 0);
+        self.flags |= INLINE_DATA;
+    }
+    pub fn is_compressed(&self) -> bool {
+        self.flags & COMPRESSED != 0
+    }
+    /// Compress the data of this empty file, in clusters of `CLUSTER_BLOCKS`
+    /// data blocks. A cluster whose first block is a hole reads back as zeros.
+    /// One whose last block is a hole is compressed, its first blocks hold
+    /// the header and the compressed data. Any other is kept as it is.
+    pub fn set_compressed(&mut self) {
+        assert!(self.size() == 0 && self.is_file());
+        self.direct.iter_mut().for_each(|v| *v = 0);
+        self.flags = (self.flags & !INLINE_DATA) | COMPRESSED;
+    }
+    fn inline_bytes(&self) -> &[u8] {
+        unsafe {
+            core::slice::from_raw_parts(self.direct.as_ptr() as *const u8, INLINE_CAPACITY)
         }
-        total as u32
     }
-    pub fn blocks_num_needed(&self, new_size: u32) -> u32 {
-        assert!(new_size >= self.size);
-        Self::total_blocks(new_size) - Self::total_blocks(self.size)
+    fn inline_bytes_mut(&mut self) -> &mut [u8] {
+        unsafe {
+            core::slice::from_raw_parts_mut(self.direct.as_mut_ptr() as *mut u8, INLINE_CAPACITY)
+        }
     }
-    pub fn get_block_id(&self, inner_id: u32, block_device: &Arc<dyn BlockDevice>) -> u32 {
-        let inner_id = inner_id as usize;
-        if inner_id < INODE_DIRECT_COUNT {
-            self.direct[inner_id]
-        } else if inner_id < INDIRECT1_BOUND {
-            get_block_cache(self.indirect1 as usize, Arc::clone(block_device))
+    /// Move inline data to a block from `alloc`, none if it is all zeros,
+    /// so that the inode refers to blocks from now on. Fails, leaving it
+    /// inline, if `alloc` does.
+    fn move_inline_data(
+        &mut self,
+        block_device: &Arc<dyn BlockDevice>,
+        alloc: &mut impl FnMut(u32) -> Option<u32>,
+    ) -> bool {
+        let mut data = [0u8; INLINE_CAPACITY];
+        data.copy_from_slice(self.inline_bytes());
+        let block_id = if data.iter().any(|byte| *byte != 0) {
+            match alloc(0) {
+                Some(block_id) => block_id,
+                None => return false,
+            }
+        } else {
+            0
+        };
+        self.flags &= !INLINE_DATA;
+        self.direct.iter_mut().for_each(|v| *v = 0);
+        self.direct[0] = block_id;
+        if block_id != 0 {
+            get_block_cache(block_id as usize, Arc::clone(block_device))
                 .lock()
-                .read(0, |indirect_block: &IndirectBlock| {
-                    indirect_block[inner_id - INODE_DIRECT_COUNT]
-                })
+                .modify_slice(0, |data_block: &mut DataBlock| {
+                    data_block[..INLINE_CAPACITY].copy_from_slice(&data);
+                });
+        }
+        true
+    }
+    pub fn size(&self) -> u64 {
+        (self.size_hi as u64) << 32 | self.size_lo as u64
+    }
+    fn set_size(&mut self, size: u64) {
+        self.size_lo = size as u32;
+        self.size_hi = (size >> 32) as u32;
+    }
+    /// Largest file an image with blocks of `block_size` bytes can hold.
+    pub fn max_size(block_size: usize) -> u64 {
+        data_block_bound(3, block_size) as u64 * block_size as u64
+    }
+    /// Return block number correspond to size.
+    pub fn data_blocks(&self, block_size: usize) -> usize {
+        self._data_blocks(self.size(), block_size)
+    }
+    /// Whole clusters for a compressed file.
+    fn _data_blocks(&self, size: u64, block_size: usize) -> usize {
+        let blocks = ((size + block_size as u64 - 1) / block_size as u64) as usize;
+        if self.is_compressed() {
+            (blocks + CLUSTER_BLOCKS - 1) / CLUSTER_BLOCKS * CLUSTER_BLOCKS
         } else {
-            let last = inner_id - INDIRECT1_BOUND;
-            let indirect1 = get_block_cache(
-                self.indirect2 as usize,
-                Arc::clone(block_device)
-            )
-            .lock()
-            .read(0, |indirect2: &IndirectBlock| {
-                indirect2[last / INODE_INDIRECT1_COUNT]
-            });
-            get_block_cache(
-                indirect1 as usize,
-                Arc::clone(block_device)
-            )
-            .lock()
-            .read(0, |indirect1: &IndirectBlock| {
-                indirect1[last % INODE_INDIRECT1_COUNT]
-            })
+            blocks
+        }
+    }
+    fn index_root(&self, depth: usize) -> u32 {
+        match depth {
+            1 => self.indirect1,
+            2 => self.indirect2,
+            _ => self.indirect3,
+        }
+    }
+    fn index_root_mut(&mut self, depth: usize) -> &mut u32 {
+        match depth {
+            1 => &mut self.indirect1,
+            2 => &mut self.indirect2,
+            _ => &mut self.indirect3,
         }
     }
-    pub fn increase_size(
+    /// Return the block holding data block `inner_id`, 0 for a hole.
+    pub fn get_block_id(&self, inner_id: u32, block_device: &Arc<dyn BlockDevice>, block_size: usize) -> u32 {
+        let inner_id = inner_id as usize;
+        if self.is_inline() {
+            return 0;
+        }
+        if inner_id < DIRECT_BOUND {
+            return self.direct[inner_id];
+        }
+        let (depth, first, _) = index_tree_of(inner_id, block_size);
+        let mut block_id = self.index_root(depth);
+        let mut idx = inner_id - first;
+        let mut span = index_span(depth, block_size);
+        for _ in 0..depth {
+            span /= block_size / 4;
+            block_id = index_entry(block_id, idx / span, block_device);
+            idx %= span;
+        }
+        block_id
+    }
+    /// Return the block holding data block `inner_id`. A hole is filled
+    /// with a block from `alloc`, so are missing index blocks on the way.
+    /// None once `alloc` runs out of blocks.
+    fn block_id_for_write(
         &mut self,
-        new_size: u32,
-        new_blocks: Vec<u32>,
+        inner_id: u32,
+        alloc: &mut impl FnMut() -> Option<u32>,
         block_device: &Arc<dyn BlockDevice>,
-    ) {
-        let mut current_blocks = self.data_blocks();
-        self.size = new_size;
-        let mut total_blocks = self.data_blocks();
-        let mut new_blocks = new_blocks.into_iter();
-        // fill direct
-        while current_blocks < total_blocks.min(INODE_DIRECT_COUNT as u32) {
-            self.direct[current_blocks as usize] = new_blocks.next().unwrap();
-            current_blocks += 1;
-        }
-        // alloc indirect1
-        if total_blocks > INODE_DIRECT_COUNT as u32{
-            if current_blocks == INODE_DIRECT_COUNT as u32 {
-                self.indirect1 = new_blocks.next().unwrap();
+        block_size: usize,
+    ) -> Option<u32> {
+        let inner_id = inner_id as usize;
+        if inner_id < DIRECT_BOUND {
+            if self.direct[inner_id] == 0 {
+                self.direct[inner_id] = alloc()?;
             }
-            current_blocks -= INODE_DIRECT_COUNT as u32;
-            total_blocks -= INODE_DIRECT_COUNT as u32;
-        } else {
-            return;
+            return Some(self.direct[inner_id]);
         }
-        // fill indirect1
-        get_block_cache(
-            self.indirect1 as usize,
-            Arc::clone(block_device)
-        )
-        .lock()
-        .modify(0, |indirect1: &mut IndirectBlock| {
-            while current_blocks < total_blocks.min(INODE_INDIRECT1_COUNT as u32) {
-                indirect1[current_blocks as usize] = new_blocks.next().unwrap();
-                current_blocks += 1;
-            }
-        });
-        // alloc indirect2
-        if total_blocks > INODE_INDIRECT1_COUNT as u32 {
-            if current_blocks == INODE_INDIRECT1_COUNT as u32 {
-                self.indirect2 = new_blocks.next().unwrap();
-            }
-            current_blocks -= INODE_INDIRECT1_COUNT as u32;
-            total_blocks -= INODE_INDIRECT1_COUNT as u32;
-        } else {
-            return;
+        let (depth, first, _) = index_tree_of(inner_id, block_size);
+        let root = self.index_root_mut(depth);
+        if *root == 0 {
+            *root = alloc()?;
         }
-        // fill indirect2 from (a0, b0) -> (a1, b1)
-        let mut a0 = current_blocks as usize / INODE_INDIRECT1_COUNT;
-        let mut b0 = current_blocks as usize % INODE_INDIRECT1_COUNT;
-        let a1 = total_blocks as usize / INODE_INDIRECT1_COUNT;
-        let b1 = total_blocks as usize % INODE_INDIRECT1_COUNT;
-        // alloc low-level indirect1
-        get_block_cache(
-            self.indirect2 as usize,
-            Arc::clone(block_device)
-        )
-        .lock()
-        .modify(0, |indirect2: &mut IndirectBlock| {
-            while (a0 < a1) || (a0 == a1 && b0 < b1) {
-                if b0 == 0 {
-                    indirect2[a0] = new_blocks.next().unwrap();
-                }
-                // fill current
-                get_block_cache(
-                    indirect2[a0] as usize,
-                    Arc::clone(block_device)
-                )
-                .lock()
-                .modify(0, |indirect1: &mut IndirectBlock| {
-                    indirect1[b0] = new_blocks.next().unwrap();
-                });
-                // move to next
-                b0 += 1;
-                if b0 == INODE_INDIRECT1_COUNT {
-                    b0 = 0;
-                    a0 += 1;
-                }
-            } 
-        });
+        let mut block_id = *root;
+        let mut idx = inner_id - first;
+        let mut span = index_span(depth, block_size);
+        for _ in 0..depth {
+            span /= block_size / 4;
+            block_id = index_entry_for_write(block_id, idx / span, alloc, block_device)?;
+            idx %= span;
+        }
+        Some(block_id)
+    }
+    /// Blocks are only allocated once written, the new range is a hole.
+    pub fn increase_size(&mut self, new_size: u64) {
+        assert!(new_size >= self.size());
+        self.set_size(new_size);
     }
 
-    /// Clear size to zero and return blocks that should be deallocated.
+    /// Shrink to `new_size` and return blocks that should be deallocated,
+    /// index blocks left with nothing to refer to included. The end of the
+    /// last block is zeroed, so that growing again reads back zeros.
     ///
-    /// We will clear the block contents to zero later.
-    pub fn clear_size(&mut self, block_device: &Arc<dyn BlockDevice>) -> Vec<u32> {
+    /// Block contents are left as they are, blocks get zeroed when allocated again.
+    pub fn decrease_size(
+        &mut self,
+        new_size: u64,
+        block_device: &Arc<dyn BlockDevice>,
+        block_size: usize,
+        checksums: bool,
+    ) -> Vec<u32> {
+        assert!(new_size <= self.size());
         let mut v: Vec<u32> = Vec::new();
-        let mut data_blocks = self.data_blocks() as usize;
-        self.size = 0;
-        let mut current_blocks = 0usize;
-        // direct
-        while current_blocks < data_blocks.min(INODE_DIRECT_COUNT) {
-            v.push(self.direct[current_blocks]);
-            self.direct[current_blocks] = 0;
-            current_blocks += 1;
-        }
-        // indirect1 block
-        if data_blocks > INODE_DIRECT_COUNT {
-            v.push(self.indirect1);
-            data_blocks -= INODE_DIRECT_COUNT;
-            current_blocks = 0;
-        } else {
+        if self.is_inline() {
+            let tail = (new_size as usize).min(INLINE_CAPACITY);
+            self.inline_bytes_mut()[tail..].iter_mut().for_each(|byte| *byte = 0);
+            self.set_size(new_size);
             return v;
         }
-        // indirect1
-        get_block_cache(
-            self.indirect1 as usize,
-            Arc::clone(block_device),
-        )
-        .lock()
-        .modify(0, |indirect1: &mut IndirectBlock| {
-            while current_blocks < data_blocks.min(INODE_INDIRECT1_COUNT) {
-                v.push(indirect1[current_blocks]);
-                //indirect1[current_blocks] = 0;
-                current_blocks += 1;
+        let old_blocks = self.data_blocks(block_size).min(data_block_bound(3, block_size));
+        let new_blocks = self._data_blocks(new_size, block_size);
+        let tail = (new_size % block_size as u64) as usize;
+        if self.is_compressed() {
+            self.truncate_cluster(new_size as usize, block_device, block_size);
+        } else if tail > 0 {
+            let block_id = self.get_block_id(new_blocks as u32 - 1, block_device, block_size);
+            let seal = self.is_dir() && checksums;
+            if block_id != 0 {
+                get_block_cache(block_id as usize, Arc::clone(block_device))
+                    .lock()
+                    .modify_slice(0, |data_block: &mut DataBlock| {
+                        data_block[tail..].iter_mut().for_each(|byte| *byte = 0);
+                        if seal {
+                            update_dir_block_checksum(data_block);
+                        }
+                    });
             }
-        });
-        self.indirect1 = 0;
-        // indirect2 block
-        if data_blocks > INODE_INDIRECT1_COUNT {
-            v.push(self.indirect2);
-            data_blocks -= INODE_INDIRECT1_COUNT;
-        } else {
-            return v;
         }
-        // indirect2
-        assert!(data_blocks <= INODE_INDIRECT2_COUNT);
-        let a1 = data_blocks / INODE_INDIRECT1_COUNT;
-        let b1 = data_blocks % INODE_INDIRECT1_COUNT;
-        get_block_cache(
-            self.indirect2 as usize,
-            Arc::clone(block_device),
-        )
-        .lock()
-        .modify(0, |indirect2: &mut IndirectBlock| {
-            // full indirect1 blocks
-            for i in 0..a1 {
-                v.push(indirect2[i]);
-                get_block_cache(
-                    indirect2[i] as usize,
-                    Arc::clone(block_device),
-                )
-                .lock()
-                .modify(0, |indirect1: &mut IndirectBlock| {
-                    for j in 0..INODE_INDIRECT1_COUNT {
-                        v.push(indirect1[j]);
-                        //indirect1[j] = 0;
-                    }
-                });
-                //indirect2[i] = 0;
+        self.set_size(new_size);
+        // direct
+        for i in new_blocks.min(DIRECT_BOUND)..old_blocks.min(DIRECT_BOUND) {
+            if self.direct[i] != 0 {
+                v.push(self.direct[i]);
+                self.direct[i] = 0;
             }
-            // last indirect1 block
-            if b1 > 0 {
-                v.push(indirect2[a1]);
-                get_block_cache(
-                    indirect2[a1] as usize,
-                    Arc::clone(block_device),
-                )
-                .lock()
-                .modify(0, |indirect1: &mut IndirectBlock| {
-                    for j in 0..b1 {
-                        v.push(indirect1[j]);
-                        //indirect1[j] = 0;
-                    }
-                });
-                //indirect2[a1] = 0;
+        }
+        // indirect1, indirect2 and indirect3
+        for (depth, first, count) in index_trees(block_size).iter() {
+            if old_blocks <= *first {
+                break;
             }
+            let from = new_blocks.saturating_sub(*first).min(*count);
+            let to = (old_blocks - first).min(*count);
+            let root = self.index_root(*depth);
+            if root != 0 && from < to && free_index_tree(root, *depth, from, to, &mut v, block_device, block_size) {
+                *self.index_root_mut(*depth) = 0;
+            }
+        }
+        v
+    }
+
+    /// Clear size to zero and return blocks that should be deallocated,
+    /// the dirent index of a directory included.
+    pub fn clear_size(
+        &mut self,
+        block_device: &Arc<dyn BlockDevice>,
+        block_size: usize,
+        checksums: bool,
+    ) -> Vec<u32> {
+        let mut v = self.decrease_size(0, block_device, block_size, checksums);
+        dir_index::visit_nodes(self.dir_index, block_device, checksums, &mut |block_id| {
+            v.push(block_id);
+            true
         });
-        self.indirect2 = 0;
+        self.dir_index = 0;
+        self.dir_free_slot = 0;
         v
     }
+    /// Visit every block id this inode refers to, index blocks and the
+    /// attribute block included and holes left out. An index block is only
+    /// read when `visit` accepts its id.
+    pub fn visit_blocks(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        block_size: usize,
+        checksums: bool,
+        mut visit: impl FnMut(u32) -> bool,
+    ) {
+        if self.xattr_block != 0 {
+            visit(self.xattr_block);
+        }
+        if self.is_inline() {
+            return;
+        }
+        let data_blocks = self.data_blocks(block_size).min(data_block_bound(3, block_size));
+        // direct
+        for i in 0..data_blocks.min(DIRECT_BOUND) {
+            if self.direct[i] != 0 {
+                visit(self.direct[i]);
+            }
+        }
+        // indirect1, indirect2 and indirect3
+        for (depth, first, count) in index_trees(block_size).iter() {
+            if data_blocks <= *first {
+                break;
+            }
+            visit_index_tree(
+                self.index_root(*depth),
+                *depth,
+                (data_blocks - first).min(*count),
+                block_device,
+                block_size,
+                &mut visit,
+            );
+        }
+        dir_index::visit_nodes(self.dir_index, block_device, checksums, &mut visit);
+    }
     pub fn read_at(
         &self,
         offset: usize,
         buf: &mut [u8],
         block_device: &Arc<dyn BlockDevice>,
+        block_size: usize,
     ) -> usize {
         let mut start = offset;
-        let end = (offset + buf.len()).min(self.size as usize);
+        let end = (offset + buf.len()).min(self.size() as usize);
         if start >= end {
             return 0;
         }
-        let mut start_block = start / BLOCK_SZ;
+        if self.is_inline() {
+            let inline = self.inline_bytes();
+            for (i, byte) in buf[..end - start].iter_mut().enumerate() {
+                *byte = inline.get(start + i).copied().unwrap_or(0);
+            }
+            return end - start;
+        }
+        if self.is_compressed() {
+            return self.read_clusters(start, &mut buf[..end - start], block_device, block_size);
+        }
+        let mut start_block = start / block_size;
         let mut read_size = 0usize;
         loop {
             // calculate end of current block
-            let mut end_current_block = (start / BLOCK_SZ + 1) * BLOCK_SZ;
+            let mut end_current_block = (start / block_size + 1) * block_size;
             end_current_block = end_current_block.min(end);
             // read and update read size
             let block_read_size = end_current_block - start;
             let dst = &mut buf[read_size..read_size + block_read_size];
-            get_block_cache(
-                self.get_block_id(start_block as u32, block_device) as usize,
-                Arc::clone(block_device),
-            )
-            .lock()
-            .read(0, |data_block: &DataBlock| {
-                let src = &data_block[start % BLOCK_SZ..start % BLOCK_SZ + block_read_size];
-                dst.copy_from_slice(src);
-            });
+            let block_id = self.get_block_id(start_block as u32, block_device, block_size);
+            if block_id == 0 {
+                // holes read back as zeros
+                dst.iter_mut().for_each(|byte| *byte = 0);
+            } else {
+                get_block_cache(block_id as usize, Arc::clone(block_device))
+                    .lock()
+                    .read_slice(0, |data_block: &DataBlock| {
+                        let src = &data_block[start % block_size..start % block_size + block_read_size];
+                        dst.copy_from_slice(src);
+                    });
+            }
             read_size += block_read_size;
             // move to next block
             if end_current_block == end { break; }
@@ -361,33 +625,79 @@ impl DiskInode {
         }
         read_size
     }
-    /// File size must be adjusted before.
+    /// File size must be adjusted before. Holes written to are filled
+    /// with blocks from `alloc`, which must hand out zeroed blocks. It is
+    /// given the block right after the data block before as a goal, 0 if
+    /// there is none, so that files stay contiguous where they can. Once
+    /// `alloc` fails, the bytes written up to there are returned.
+    ///
+    /// Directory blocks get their checksum updated on images with checksums,
+    /// their checksum slots must be left alone.
+    ///
+    /// A compressed file is written a cluster at a time, the bytes returned
+    /// stop at the last cluster that could be stored.
     pub fn write_at(
         &mut self,
         offset: usize,
         buf: &[u8],
         block_device: &Arc<dyn BlockDevice>,
+        block_size: usize,
+        checksums: bool,
+        mut alloc: impl FnMut(u32) -> Option<u32>,
     ) -> usize {
         let mut start = offset;
-        let end = (offset + buf.len()).min(self.size as usize);
+        let end = (offset + buf.len()).min(self.size() as usize);
         assert!(start <= end);
-        let mut start_block = start / BLOCK_SZ;
+        if start == end {
+            return 0;
+        }
+        if self.is_inline() {
+            if end <= INLINE_CAPACITY {
+                self.inline_bytes_mut()[start..end].copy_from_slice(&buf[..end - start]);
+                return end - start;
+            }
+            if !self.move_inline_data(block_device, &mut alloc) {
+                return 0;
+            }
+        }
+        if self.is_compressed() {
+            return self.write_clusters(start, &buf[..end - start], block_device, block_size, &mut alloc);
+        }
+        let seal = self.is_dir() && checksums;
+        let mut start_block = start / block_size;
         let mut write_size = 0usize;
+        let mut goal = match start_block {
+            0 => 0,
+            _ => match self.get_block_id(start_block as u32 - 1, block_device, block_size) {
+                0 => 0,
+                block_id => block_id + 1,
+            },
+        };
         loop {
             // calculate end of current block
-            let mut end_current_block = (start / BLOCK_SZ + 1) * BLOCK_SZ;
+            let mut end_current_block = (start / block_size + 1) * block_size;
             end_current_block = end_current_block.min(end);
             // write and update write size
             let block_write_size = end_current_block - start;
-            get_block_cache(
-                self.get_block_id(start_block as u32, block_device) as usize,
-                Arc::clone(block_device)
-            )
+            let block_id = match self.block_id_for_write(
+                start_block as u32,
+                &mut || alloc(goal),
+                block_device,
+                block_size,
+            ) {
+                Some(block_id) => block_id,
+                None => break,
+            };
+            goal = block_id + 1;
+            get_block_cache(block_id as usize, Arc::clone(block_device))
             .lock()
-            .modify(0, |data_block: &mut DataBlock| {
+            .modify_slice(0, |data_block: &mut DataBlock| {
                 let src = &buf[write_size..write_size + block_write_size];
-                let dst = &mut data_block[start % BLOCK_SZ..start % BLOCK_SZ + block_write_size];
+                let dst = &mut data_block[start % block_size..start % block_size + block_write_size];
                 dst.copy_from_slice(src);
+                if seal {
+                    update_dir_block_checksum(data_block);
+                }
             });
             write_size += block_write_size;
             // move to next block
@@ -397,50 +707,389 @@ impl DiskInode {
         }
         write_size
     }
+    /// Block ids of the data blocks of cluster `cluster`, 0 for holes.
+    fn cluster_block_ids(&self, cluster: usize, block_device: &Arc<dyn BlockDevice>, block_size: usize) -> Vec<u32> {
+        let first = cluster * CLUSTER_BLOCKS;
+        (first..first + CLUSTER_BLOCKS)
+            .map(|inner_id| self.get_block_id(inner_id as u32, block_device, block_size))
+            .collect()
+    }
+    /// Read cluster `cluster` into `data`, a cluster large. Compressed data
+    /// that turns out malformed reads back as zeros from where it fails.
+    fn read_cluster(
+        &self,
+        cluster: usize,
+        data: &mut [u8],
+        block_device: &Arc<dyn BlockDevice>,
+        block_size: usize,
+    ) {
+        let block_ids = self.cluster_block_ids(cluster, block_device, block_size);
+        data.iter_mut().for_each(|byte| *byte = 0);
+        if block_ids[0] == 0 {
+            return;
+        }
+        let read_block = |block_id: u32, dst: &mut [u8]| {
+            if block_id != 0 {
+                get_block_cache(block_id as usize, Arc::clone(block_device))
+                    .lock()
+                    .read_slice(0, |data_block: &DataBlock| dst.copy_from_slice(data_block));
+            }
+        };
+        if block_ids[CLUSTER_BLOCKS - 1] != 0 {
+            for (block_id, dst) in block_ids.iter().zip(data.chunks_mut(block_size)) {
+                read_block(*block_id, dst);
+            }
+            return;
+        }
+        let mut header = [0u8; CLUSTER_HEADER_SZ];
+        get_block_cache(block_ids[0] as usize, Arc::clone(block_device))
+            .lock()
+            .read_slice(0, |data_block: &DataBlock| {
+                header.copy_from_slice(&data_block[..CLUSTER_HEADER_SZ]);
+            });
+        let compressed_len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let data_len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        let stored = (CLUSTER_HEADER_SZ + compressed_len).min((CLUSTER_BLOCKS - 1) * block_size);
+        let mut stream = vec![0u8; (stored + block_size - 1) / block_size * block_size];
+        for (block_id, dst) in block_ids.iter().zip(stream.chunks_mut(block_size)) {
+            read_block(*block_id, dst);
+        }
+        decompress(&stream[CLUSTER_HEADER_SZ..stored], data);
+        // data past its length was truncated
+        if data_len < data.len() {
+            data[data_len..].iter_mut().for_each(|byte| *byte = 0);
+        }
+    }
+    /// Store `data`, the whole of cluster `cluster` of which `data_len` bytes
+    /// are in the file. It is compressed if that saves a block, unless the
+    /// cluster is kept uncompressed already. Blocks are only ever added, and
+    /// all of them before anything is written, so that a failing `alloc`
+    /// leaves the cluster as it was.
+    fn write_cluster(
+        &mut self,
+        cluster: usize,
+        data: &[u8],
+        data_len: usize,
+        block_device: &Arc<dyn BlockDevice>,
+        block_size: usize,
+        alloc: &mut impl FnMut(u32) -> Option<u32>,
+    ) -> bool {
+        let first = cluster * CLUSTER_BLOCKS;
+        let mut stream = Vec::new();
+        if self.get_block_id((first + CLUSTER_BLOCKS - 1) as u32, block_device, block_size) == 0 {
+            let compressed = compress(&data[..data_len]);
+            stream.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+            stream.extend_from_slice(&(data_len as u32).to_le_bytes());
+            stream.extend_from_slice(&compressed);
+        }
+        let src = if stream.is_empty() || stream.len() > (CLUSTER_BLOCKS - 1) * block_size {
+            data
+        } else {
+            stream.as_slice()
+        };
+        let mut goal = match first {
+            0 => 0,
+            _ => match self.get_block_id(first as u32 - 1, block_device, block_size) {
+                0 => 0,
+                block_id => block_id + 1,
+            },
+        };
+        let mut block_ids = Vec::new();
+        for inner_id in first..first + (src.len() + block_size - 1) / block_size {
+            let block_id = match self.block_id_for_write(
+                inner_id as u32,
+                &mut || alloc(goal),
+                block_device,
+                block_size,
+            ) {
+                Some(block_id) => block_id,
+                None => return false,
+            };
+            goal = block_id + 1;
+            block_ids.push(block_id);
+        }
+        for (block_id, chunk) in block_ids.iter().zip(src.chunks(block_size)) {
+            get_block_cache(*block_id as usize, Arc::clone(block_device))
+                .lock()
+                .modify_slice(0, |data_block: &mut DataBlock| {
+                    data_block[..chunk.len()].copy_from_slice(chunk);
+                });
+        }
+        true
+    }
+    fn read_clusters(
+        &self,
+        offset: usize,
+        buf: &mut [u8],
+        block_device: &Arc<dyn BlockDevice>,
+        block_size: usize,
+    ) -> usize {
+        let cluster_size = CLUSTER_BLOCKS * block_size;
+        let mut data = vec![0u8; cluster_size];
+        let mut read_size = 0usize;
+        while read_size < buf.len() {
+            let start = offset + read_size;
+            let cluster = start / cluster_size;
+            let len = (cluster_size - start % cluster_size).min(buf.len() - read_size);
+            self.read_cluster(cluster, &mut data, block_device, block_size);
+            buf[read_size..read_size + len]
+                .copy_from_slice(&data[start % cluster_size..start % cluster_size + len]);
+            read_size += len;
+        }
+        read_size
+    }
+    /// Clusters written to are read, patched and stored again, a cluster
+    /// at a time.
+    fn write_clusters(
+        &mut self,
+        offset: usize,
+        buf: &[u8],
+        block_device: &Arc<dyn BlockDevice>,
+        block_size: usize,
+        alloc: &mut impl FnMut(u32) -> Option<u32>,
+    ) -> usize {
+        let cluster_size = CLUSTER_BLOCKS * block_size;
+        let size = self.size() as usize;
+        let mut data = vec![0u8; cluster_size];
+        let mut write_size = 0usize;
+        while write_size < buf.len() {
+            let start = offset + write_size;
+            let cluster = start / cluster_size;
+            let len = (cluster_size - start % cluster_size).min(buf.len() - write_size);
+            if len < cluster_size {
+                self.read_cluster(cluster, &mut data, block_device, block_size);
+            }
+            data[start % cluster_size..start % cluster_size + len]
+                .copy_from_slice(&buf[write_size..write_size + len]);
+            let data_len = (size - cluster * cluster_size).min(cluster_size);
+            if !self.write_cluster(cluster, &data, data_len, block_device, block_size, alloc) {
+                break;
+            }
+            write_size += len;
+        }
+        write_size
+    }
+    /// Drop the data of the last cluster past `new_size`, so that growing
+    /// again reads back zeros. A compressed cluster only gets a shorter
+    /// length, a cluster kept as it is gets zeroed.
+    fn truncate_cluster(&self, new_size: usize, block_device: &Arc<dyn BlockDevice>, block_size: usize) {
+        let cluster_size = CLUSTER_BLOCKS * block_size;
+        let tail = new_size % cluster_size;
+        let block_ids = self.cluster_block_ids(new_size / cluster_size, block_device, block_size);
+        if tail == 0 || block_ids[0] == 0 {
+            return;
+        }
+        if block_ids[CLUSTER_BLOCKS - 1] == 0 {
+            get_block_cache(block_ids[0] as usize, Arc::clone(block_device))
+                .lock()
+                .modify_slice(0, |data_block: &mut DataBlock| {
+                    let data_len = &mut data_block[4..CLUSTER_HEADER_SZ];
+                    let old_len = u32::from_le_bytes([data_len[0], data_len[1], data_len[2], data_len[3]]);
+                    data_len.copy_from_slice(&old_len.min(tail as u32).to_le_bytes());
+                });
+            return;
+        }
+        for (i, block_id) in block_ids.iter().enumerate() {
+            let from = tail.saturating_sub(i * block_size);
+            if from < block_size {
+                get_block_cache(*block_id as usize, Arc::clone(block_device))
+                    .lock()
+                    .modify_slice(0, |data_block: &mut DataBlock| {
+                        data_block[from..].iter_mut().for_each(|byte| *byte = 0);
+                    });
+            }
+        }
+    }
+}
+
+/// Entry `idx` of index block `index_block`, 0 if the index block is a hole.
+fn index_entry(index_block: u32, idx: usize, block_device: &Arc<dyn BlockDevice>) -> u32 {
+    if index_block == 0 {
+        return 0;
+    }
+    get_block_cache(index_block as usize, Arc::clone(block_device))
+        .lock()
+        .read_slice(0, |indirect_block: &IndirectBlock| indirect_block[idx])
+}
+
+/// Data blocks an index tree of `depth` covers.
+fn index_span(depth: usize, block_size: usize) -> usize {
+    (block_size / 4).pow(depth as u32)
 }
 
+/// The index tree data block `inner_id` belongs to, past the direct blocks.
+fn index_tree_of(inner_id: usize, block_size: usize) -> (usize, usize, usize) {
+    *index_trees(block_size)
+        .iter()
+        .find(|(_, first, count)| inner_id < first + count)
+        .unwrap()
+}
+
+/// Visit `block_id` and, as long as `visit` accepts index blocks, the
+/// first `count` data blocks of the index tree of `depth` below it.
+fn visit_index_tree<F: FnMut(u32) -> bool>(
+    block_id: u32,
+    depth: usize,
+    count: usize,
+    block_device: &Arc<dyn BlockDevice>,
+    block_size: usize,
+    visit: &mut F,
+) {
+    if block_id == 0 || !visit(block_id) || depth == 0 {
+        return;
+    }
+    let span = index_span(depth - 1, block_size);
+    get_block_cache(block_id as usize, Arc::clone(block_device))
+        .lock()
+        .read_slice(0, |indirect_block: &IndirectBlock| {
+            let entries = (count + span - 1) / span;
+            for (i, sub_block) in indirect_block.iter().enumerate().take(entries) {
+                let sub_count = (count - i * span).min(span);
+                visit_index_tree(*sub_block, depth - 1, sub_count, block_device, block_size, visit);
+            }
+        });
+}
+
+/// Collect the blocks behind data blocks `from..to` of the index tree of
+/// `depth` at `block_id` into `v`, clearing the entries that lead to them.
+/// With `from` being 0 the whole tree goes, `block_id` included, and true
+/// is returned.
+fn free_index_tree(
+    block_id: u32,
+    depth: usize,
+    from: usize,
+    to: usize,
+    v: &mut Vec<u32>,
+    block_device: &Arc<dyn BlockDevice>,
+    block_size: usize,
+) -> bool {
+    if from == 0 {
+        visit_index_tree(block_id, depth, to, block_device, block_size, &mut |freed| {
+            v.push(freed);
+            true
+        });
+        return true;
+    }
+    let span = index_span(depth - 1, block_size);
+    get_block_cache(block_id as usize, Arc::clone(block_device))
+        .lock()
+        .modify_slice(0, |indirect_block: &mut IndirectBlock| {
+            let (first, end) = (from / span, (to + span - 1) / span);
+            for (i, sub_block) in indirect_block.iter_mut().enumerate().take(end).skip(first) {
+                if *sub_block == 0 {
+                    continue;
+                }
+                let base = i * span;
+                let (sub_from, sub_to) = (from.max(base) - base, to.min(base + span) - base);
+                if free_index_tree(*sub_block, depth - 1, sub_from, sub_to, v, block_device, block_size) {
+                    *sub_block = 0;
+                }
+            }
+        });
+    false
+}
+
+/// Entry `idx` of index block `index_block`, filled from `alloc` if it is a hole.
+fn index_entry_for_write(
+    index_block: u32,
+    idx: usize,
+    alloc: &mut impl FnMut() -> Option<u32>,
+    block_device: &Arc<dyn BlockDevice>,
+) -> Option<u32> {
+    let block_id = index_entry(index_block, idx, block_device);
+    if block_id != 0 {
+        return Some(block_id);
+    }
+    let block_id = alloc()?;
+    get_block_cache(index_block as usize, Arc::clone(block_device))
+        .lock()
+        .modify_slice(0, |indirect_block: &mut IndirectBlock| {
+            indirect_block[idx] = block_id;
+        });
+    Some(block_id)
+}
+
+pub const NAME_LENGTH_LIMIT: usize = 255;
+/// Directory data is divided into slots of this size.
+pub const DIRENT_SZ: usize = 32;
+const DIRENT_HEADER_SZ: usize = 5;
+const DIRENT_MAX_SLOTS: usize = (DIRENT_HEADER_SZ + NAME_LENGTH_LIMIT + DIRENT_SZ - 1) / DIRENT_SZ;
+
+/// On images with checksums, the last slot of every directory block holds
+/// the checksum of the block rather than a dirent, and dirents never span it.
+pub fn is_checksum_slot(slot: usize, block_size: usize) -> bool {
+    (slot + 1) % (block_size / DIRENT_SZ) == 0
+}
+
+pub fn update_dir_block_checksum(block: &mut DataBlock) {
+    let end = block.len() - 4;
+    let checksum = crc32(&block[..end]);
+    block[end..].copy_from_slice(&checksum.to_le_bytes());
+}
+
+pub fn dir_block_is_valid(block: &DataBlock) -> bool {
+    let end = block.len() - 4;
+    block[end..] == crc32(&block[..end]).to_le_bytes()[..]
+}
+
+/// A dirent takes as many consecutive slots as its name needs: the header
+/// is followed by the name right away. A slot whose name length is zero is
+/// free, while the slots after a header are parts of the same dirent.
 #[repr(C)]
 pub struct DirEntry {
-    name: [u8; NAME_LENGTH_LIMIT + 1],
     inode_number: u32,
+    name_len: u8,
+    name: [u8; DIRENT_MAX_SLOTS * DIRENT_SZ - DIRENT_HEADER_SZ],
 }
 
-pub const DIRENT_SZ: usize = 32;
-
 impl DirEntry {
     pub fn empty() -> Self {
         Self {
-            name: [0u8; NAME_LENGTH_LIMIT + 1],
             inode_number: 0,
+            name_len: 0,
+            name: [0u8; DIRENT_MAX_SLOTS * DIRENT_SZ - DIRENT_HEADER_SZ],
         }
     }
     pub fn new(name: &str, inode_number: u32) -> Self {
-        let mut bytes = [0u8; NAME_LENGTH_LIMIT + 1];
-        &mut bytes[..name.len()].copy_from_slice(name.as_bytes());
-        Self {
-            name: bytes,
-            inode_number,
+        assert!(!name.is_empty() && name.len() <= NAME_LENGTH_LIMIT);
+        let mut dirent = Self::empty();
+        dirent.name[..name.len()].copy_from_slice(name.as_bytes());
+        dirent.name_len = name.len() as u8;
+        dirent.inode_number = inode_number;
+        dirent
+    }
+    /// Number of slots taken on disk.
+    pub fn slots(&self) -> usize {
+        if self.is_empty() {
+            1
+        } else {
+            (DIRENT_HEADER_SZ + self.name_len as usize + DIRENT_SZ - 1) / DIRENT_SZ
         }
     }
     pub fn as_bytes(&self) -> &[u8] {
         unsafe {
             core::slice::from_raw_parts(
                 self as *const _ as usize as *const u8,
-                DIRENT_SZ,
+                self.slots() * DIRENT_SZ,
             )
         }
     }
+    /// The whole buffer, large enough for the longest dirent.
     pub fn as_bytes_mut(&mut self) -> &mut [u8] {
         unsafe {
             core::slice::from_raw_parts_mut(
                 self as *mut _ as usize as *mut u8,
-                DIRENT_SZ,
+                DIRENT_MAX_SLOTS * DIRENT_SZ,
             )
         }
     }
-    pub fn name(&self) -> &str {
-        let len = (0usize..).find(|i| self.name[*i] == 0).unwrap();
-        core::str::from_utf8(&self.name[..len]).unwrap()
+    pub fn is_empty(&self) -> bool {
+        self.name_len == 0
+    }
+    /// Fails on a name that is not UTF-8, which no dirent written by
+    /// easy-fs has.
+    pub fn name(&self) -> Result<&str> {
+        core::str::from_utf8(&self.name[..self.name_len as usize]).map_err(|_| Error::Corrupt)
     }
     pub fn inode_number(&self) -> u32 {
         self.inode_number
@@ -3,16 +3,40 @@
 extern crate alloc;
 
 mod block_dev;
+mod ram_block_dev;
 mod layout;
 mod efs;
 mod bitmap;
 mod vfs;
 mod block_cache;
+mod journal;
+mod fsck;
+mod error;
+mod dir_index;
+mod xattr;
+mod checksum;
+mod compress;
 
+/// Size of a sector, the unit `BlockDevice` transfers in. Also the smallest
+/// and the default block size.
 pub const BLOCK_SZ: usize = 512;
 pub use block_dev::BlockDevice;
-pub use efs::EasyFileSystem;
-pub use vfs::Inode;
+pub use ram_block_dev::{RamBlockDevice, Fault};
+pub use efs::{EasyFileSystem, FsStat};
+pub use vfs::{Inode, InodeStat};
+pub use layout::DiskInodeType;
+pub use fsck::FsckProblem;
+pub use error::{Error, Result};
 use layout::*;
 use bitmap::Bitmap;
-use block_cache::get_block_cache;
\ No newline at end of file
+use block_cache::{
+    BlockCache,
+    get_block_cache,
+    dirty_block_caches,
+    reserve_block_caches,
+    set_block_size,
+};
+use journal::Journal;
+use xattr::*;
+use checksum::{crc32, crc32_update};
+use compress::{compress, decompress};
\ No newline at end of file
@@ -0,0 +1,107 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+use super::{
+    BLOCK_SZ,
+    BlockDevice,
+};
+
+/// What happens to the writes of a `RamBlockDevice` once a fault is due.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Writes are dropped, as if power was lost before them.
+    Fail,
+    /// Only the first bytes of the first write get through, as if power
+    /// was lost while it went on, and later writes are dropped.
+    Torn(usize),
+}
+
+struct RamBlockDeviceInner {
+    data: Vec<u8>,
+    writes: usize,
+    /// Writes still let through before `Fault` comes into effect.
+    fault: Option<(usize, Fault)>,
+}
+
+/// A block device kept in memory, which can also fail writes on purpose
+/// to test what an image looks like after a crash.
+pub struct RamBlockDevice {
+    inner: Mutex<RamBlockDeviceInner>,
+}
+
+impl RamBlockDevice {
+    /// A zeroed device of `sectors` sectors.
+    pub fn new(sectors: usize) -> Self {
+        Self::from_image(vec![0u8; sectors * BLOCK_SZ])
+    }
+
+    /// A device holding `image`, whose size must be whole sectors.
+    pub fn from_image(image: Vec<u8>) -> Self {
+        assert_eq!(image.len() % BLOCK_SZ, 0, "Image is not made of whole sectors!");
+        Self {
+            inner: Mutex::new(RamBlockDeviceInner {
+                data: image,
+                writes: 0,
+                fault: None,
+            }),
+        }
+    }
+
+    pub fn sectors(&self) -> usize {
+        self.inner.lock().data.len() / BLOCK_SZ
+    }
+
+    /// Let `writes` more writes through, then run into `fault`.
+    pub fn inject_fault(&self, writes: usize, fault: Fault) {
+        self.inner.lock().fault = Some((writes, fault));
+    }
+
+    pub fn clear_fault(&self) {
+        self.inner.lock().fault = None;
+    }
+
+    /// Writes that got through so far, torn ones included.
+    pub fn writes(&self) -> usize {
+        self.inner.lock().writes
+    }
+
+    /// A new device holding what this one holds, e.g. whatever survived a
+    /// crash. Its block cache is not shared with this one.
+    pub fn snapshot(&self) -> Self {
+        Self::from_image(self.inner.lock().data.clone())
+    }
+
+    /// The contents of the device.
+    pub fn image(&self) -> Vec<u8> {
+        self.inner.lock().data.clone()
+    }
+}
+
+impl BlockDevice for RamBlockDevice {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+        let inner = self.inner.lock();
+        let start = block_id * BLOCK_SZ;
+        assert!(start + buf.len() <= inner.data.len(), "Reading past the end of RamBlockDevice!");
+        buf.copy_from_slice(&inner.data[start..start + buf.len()]);
+    }
+
+    fn write_block(&self, block_id: usize, buf: &[u8]) {
+        let mut inner = self.inner.lock();
+        let start = block_id * BLOCK_SZ;
+        assert!(start + buf.len() <= inner.data.len(), "Writing past the end of RamBlockDevice!");
+        let len = match inner.fault {
+            None => buf.len(),
+            Some((0, Fault::Fail)) => return,
+            Some((0, Fault::Torn(len))) => {
+                inner.fault = Some((0, Fault::Fail));
+                len.min(buf.len())
+            }
+            Some((writes, fault)) => {
+                inner.fault = Some((writes - 1, fault));
+                buf.len()
+            }
+        };
+        inner.data[start..start + len].copy_from_slice(&buf[..len]);
+        inner.writes += 1;
+    }
+}
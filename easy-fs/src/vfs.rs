@@ -5,201 +5,1172 @@ use super::{
     DirEntry,
     EasyFileSystem,
     DIRENT_SZ,
+    NAME_LENGTH_LIMIT,
+    MODE_MASK,
+    XATTR_NAME_LIMIT,
+    parse_xattrs,
+    xattrs_size,
+    write_xattrs,
+    Error,
+    Result,
     get_block_cache,
+    is_checksum_slot,
+    dir_block_is_valid,
+    dir_index,
 };
+use dir_index::{Key, name_hash};
 use alloc::sync::Arc;
 use alloc::string::String;
+use alloc::vec;
 use alloc::vec::Vec;
 use spin::{Mutex, MutexGuard};
 
+/// File data blocks written per transaction by `Inode::write_at`, which
+/// keeps a transaction well within the journal however large the write is.
+const WRITE_TXN_BLOCKS: usize = 8;
+/// Reads refresh atime only when it is older than mtime or than this many
+/// milliseconds, so that reading does not commit a transaction every time.
+const ATIME_INTERVAL: u64 = 60 * 60 * 1000;
+
+type DataBlock = [u8];
+
+/// Names must fit a dirent and cannot contain a path separator.
+fn check_name(name: &str) -> Result<()> {
+    if name.len() > NAME_LENGTH_LIMIT {
+        Err(Error::NameTooLong)
+    } else if name.is_empty() || name.contains('/') {
+        Err(Error::InvalidName)
+    } else {
+        Ok(())
+    }
+}
+
+/// Names of extended attributes are not empty and fit an attribute entry.
+fn check_xattr_name(name: &str) -> Result<()> {
+    if name.len() > XATTR_NAME_LIMIT {
+        Err(Error::NameTooLong)
+    } else if name.is_empty() {
+        Err(Error::InvalidName)
+    } else {
+        Ok(())
+    }
+}
+
+/// Attributes of an inode as returned by `Inode::stat`.
+pub struct InodeStat {
+    pub inode_id: u32,
+    pub type_: DiskInodeType,
+    pub size: u64,
+    pub nlink: u16,
+    pub atime: u64,
+    pub mtime: u64,
+    pub ctime: u64,
+    pub uid: u32,
+    pub gid: u32,
+    pub mode: u16,
+    /// Whether the data is compressed.
+    pub compressed: bool,
+}
+
 pub struct Inode {
+    inode_id: u32,
     block_id: usize,
     block_offset: usize,
     fs: Arc<Mutex<EasyFileSystem>>,
     block_device: Arc<dyn BlockDevice>,
+    /// Copied from the file system, which is not locked for every block.
+    block_size: usize,
+    checksums: bool,
 }
 
 impl Inode {
-    /// We should not acquire efs lock here.
+    /// We should not acquire efs lock here, `block_size` and `checksums`
+    /// are the ones of the file system.
     pub fn new(
+        inode_id: u32,
         block_id: u32,
         block_offset: usize,
         fs: Arc<Mutex<EasyFileSystem>>,
         block_device: Arc<dyn BlockDevice>,
+        block_size: usize,
+        checksums: bool,
     ) -> Self {
         Self {
+            inode_id,
             block_id: block_id as usize,
             block_offset,
             fs,
             block_device,
+            block_size,
+            checksums,
         }
     }
 
-    fn read_disk_inode<V>(&self, f: impl FnOnce(&DiskInode) -> V) -> V {
-        get_block_cache(
-            self.block_id,
-            Arc::clone(&self.block_device)
-        ).lock().read(self.block_offset, f)
+    /// Fails if the inode block does not match its checksums.
+    fn read_disk_inode<V>(&self, f: impl FnOnce(&DiskInode) -> V) -> Result<V> {
+        let checksums = self.checksums;
+        let block_cache = get_block_cache(self.block_id, Arc::clone(&self.block_device));
+        let mut block_cache = block_cache.lock();
+        if checksums {
+            block_cache.verify(DiskInode::block_is_valid)?;
+        }
+        Ok(block_cache.read(self.block_offset, f))
     }
 
-    fn modify_disk_inode<V>(&self, f: impl FnOnce(&mut DiskInode) -> V) -> V {
-        get_block_cache(
-            self.block_id,
-            Arc::clone(&self.block_device)
-        ).lock().modify(self.block_offset, f)
+    /// Same as `read_disk_inode`, the checksum is brought up to date after `f`.
+    fn modify_disk_inode<V>(&self, f: impl FnOnce(&mut DiskInode) -> V) -> Result<V> {
+        let checksums = self.checksums;
+        let block_cache = get_block_cache(self.block_id, Arc::clone(&self.block_device));
+        let mut block_cache = block_cache.lock();
+        if checksums {
+            block_cache.verify(DiskInode::block_is_valid)?;
+        }
+        Ok(block_cache.modify(self.block_offset, |disk_inode: &mut DiskInode| {
+            let v = f(disk_inode);
+            if checksums {
+                disk_inode.update_checksum();
+            }
+            v
+        }))
     }
 
-    fn find_inode_id(
+    /// Fails if the directory block of `slot` does not match its checksum,
+    /// or if the dirent is cut off by the end of the directory or its name
+    /// is not UTF-8, so that the dirents visited have a name.
+    fn read_dirent(&self, disk_inode: &DiskInode, slot: usize) -> Result<DirEntry> {
+        if self.checksums {
+            let block_id = disk_inode.get_block_id(
+                (slot * DIRENT_SZ / self.block_size) as u32,
+                &self.block_device,
+                self.block_size,
+            );
+            get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+                .lock()
+                .verify(dir_block_is_valid)?;
+        }
+        let mut dirent = DirEntry::empty();
+        let read_size = disk_inode.read_at(
+            DIRENT_SZ * slot,
+            &mut dirent.as_bytes_mut()[..DIRENT_SZ],
+            &self.block_device,
+            self.block_size,
+        );
+        if read_size != DIRENT_SZ {
+            return Err(Error::Corrupt);
+        }
+        // the rest of a long name lives in the following slots
+        let size = dirent.slots() * DIRENT_SZ;
+        if size > DIRENT_SZ {
+            let read_size = disk_inode.read_at(
+                DIRENT_SZ * (slot + 1),
+                &mut dirent.as_bytes_mut()[DIRENT_SZ..size],
+                &self.block_device,
+                self.block_size,
+            );
+            if read_size != size - DIRENT_SZ {
+                return Err(Error::Corrupt);
+            }
+        }
+        dirent.name()?;
+        Ok(dirent)
+    }
+
+    /// Visit dirents in order along with their slot index, free slots
+    /// included, until `f` returns something. Checksum slots are skipped.
+    fn walk_dirents<V>(
+        &self,
+        disk_inode: &DiskInode,
+        f: impl FnMut(usize, DirEntry) -> Option<V>,
+    ) -> Result<Option<V>> {
+        self.walk_dirents_from(disk_inode, 0, f)
+    }
+
+    /// Same as `walk_dirents`, starting at `slot`, which must be where a
+    /// dirent, a free slot or a checksum slot starts.
+    fn walk_dirents_from<V>(
         &self,
-        name: &str,
         disk_inode: &DiskInode,
-    ) -> Option<u32> {
+        mut slot: usize,
+        mut f: impl FnMut(usize, DirEntry) -> Option<V>,
+    ) -> Result<Option<V>> {
         // assert it is a directory
         assert!(disk_inode.is_dir());
-        let file_count = (disk_inode.size as usize) / DIRENT_SZ;
-        let mut dirent = DirEntry::empty();
-        for i in 0..file_count {
-            assert_eq!(
-                disk_inode.read_at(
-                    DIRENT_SZ * i,
-                    dirent.as_bytes_mut(),
-                    &self.block_device,
-                ),
-                DIRENT_SZ,
-            );
-            if dirent.name() == name {
-                return Some(dirent.inode_number() as u32);
+        let slot_count = (disk_inode.size() as usize) / DIRENT_SZ;
+        while slot < slot_count {
+            if self.checksums && is_checksum_slot(slot, self.block_size) {
+                slot += 1;
+                continue;
             }
+            let dirent = self.read_dirent(disk_inode, slot)?;
+            let slots = dirent.slots();
+            if let Some(v) = f(slot, dirent) {
+                return Ok(Some(v));
+            }
+            slot += slots;
         }
-        None
+        Ok(None)
+    }
+
+    /// Return the slot index of dirent `name` along with the dirent.
+    fn find_dirent(
+        &self,
+        name: &str,
+        disk_inode: &DiskInode,
+    ) -> Result<Option<(usize, DirEntry)>> {
+        if disk_inode.dir_index != 0 {
+            let mut found = Ok(None);
+            let hash = name_hash(name);
+            dir_index::find(disk_inode.dir_index, hash, &self.block_device, self.checksums, &mut |slot| {
+                match self.read_dirent(disk_inode, slot as usize) {
+                    Ok(dirent) if dirent.is_empty() || dirent.name() != Ok(name) => false,
+                    dirent => {
+                        found = dirent.map(|dirent| Some((slot as usize, dirent)));
+                        true
+                    }
+                }
+            })?;
+            return found;
+        }
+        self.walk_dirents(disk_inode, |slot, dirent| {
+            if !dirent.is_empty() && dirent.name() == Ok(name) {
+                Some((slot, dirent))
+            } else {
+                None
+            }
+        })
     }
 
-    pub fn find(&self, name: &str) -> Option<Arc<Inode>> {
+    fn find_inode_id(
+        &self,
+        name: &str,
+        disk_inode: &DiskInode,
+    ) -> Result<Option<u32>> {
+        Ok(self.find_dirent(name, disk_inode)?
+            .map(|(_, dirent)| dirent.inode_number()))
+    }
+
+    fn get_inode(&self, inode_id: u32, fs: &EasyFileSystem) -> Arc<Inode> {
+        let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
+        Arc::new(Self::new(
+            inode_id,
+            block_id,
+            block_offset,
+            self.fs.clone(),
+            self.block_device.clone(),
+            self.block_size,
+            self.checksums,
+        ))
+    }
+
+    pub fn inode_id(&self) -> u32 {
+        self.inode_id
+    }
+
+    /// False for an inode that fails its checksum.
+    pub fn is_dir(&self) -> bool {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| disk_inode.is_dir()) == Ok(true)
+    }
+
+    pub fn find(&self, name: &str) -> Result<Arc<Inode>> {
         let fs = self.fs.lock();
         self.read_disk_inode(|disk_inode| {
-            self.find_inode_id(name, disk_inode)
-            .map(|inode_id| {
-                let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
-                Arc::new(Self::new(
-                    block_id,
-                    block_offset,
-                    self.fs.clone(),
-                    self.block_device.clone(),
-                ))
-            })
-        })
+            if !disk_inode.is_dir() {
+                return Err(Error::NotDir);
+            }
+            self.find_inode_id(name, disk_inode)?
+            .map(|inode_id| self.get_inode(inode_id, &fs))
+            .ok_or(Error::NotFound)
+        })?
+    }
+
+    /// Walk `path` component by component. An absolute path starts from
+    /// the root directory, otherwise from this inode. Symbolic links are
+    /// not followed.
+    pub fn find_path(&self, path: &str) -> Option<Arc<Inode>> {
+        let mut inode = {
+            let fs = self.fs.lock();
+            let start_id = if path.starts_with('/') { 0 } else { self.inode_id };
+            self.get_inode(start_id, &fs)
+        };
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            if !inode.is_dir() {
+                return None;
+            }
+            inode = inode.find(name).ok()?;
+        }
+        Some(inode)
     }
 
-    fn increase_size(
+    /// Add a dirent to the directory `dir_inode` belongs to, reusing free
+    /// slots left by removed entries when enough of them are in a row. A
+    /// dirent that would span a checksum slot goes to the next block.
+    fn append_dirent(
         &self,
-        new_size: u32,
-        disk_inode: &mut DiskInode,
+        name: &str,
+        inode_id: u32,
+        dir_inode: &mut DiskInode,
         fs: &mut MutexGuard<EasyFileSystem>,
-    ) {
-        if new_size < disk_inode.size {
+    ) -> Result<()> {
+        let dirent = DirEntry::new(name, inode_id);
+        let slots_needed = dirent.slots();
+        let slot_count = (dir_inode.size() as usize) / DIRENT_SZ;
+        // indexed directories have no free slot before dir_free_slot
+        let from = if dir_inode.dir_index != 0 { dir_inode.dir_free_slot as usize } else { 0 };
+        let mut first_free = slot_count;
+        let mut run_start = 0usize;
+        let mut run_len = 0usize;
+        let found = self.walk_dirents_from(dir_inode, from, |slot, dirent| {
+            // runs are broken by checksum slots
+            if !dirent.is_empty() || slot != run_start + run_len {
+                run_len = 0;
+            }
+            if !dirent.is_empty() {
+                return None;
+            }
+            first_free = first_free.min(slot);
+            if run_len == 0 {
+                run_start = slot;
+            }
+            run_len += 1;
+            if run_len == slots_needed {
+                Some(run_start)
+            } else {
+                None
+            }
+        })?;
+        let slot = found.unwrap_or_else(|| {
+            // free slots at the end can still be extended
+            let slot = if run_len > 0 && run_start + run_len == slot_count { run_start } else { slot_count };
+            let slots_per_block = fs.block_size() / DIRENT_SZ;
+            if fs.checksums() && slot % slots_per_block + slots_needed >= slots_per_block {
+                (slot / slots_per_block + 1) * slots_per_block
+            } else {
+                slot
+            }
+        });
+        let new_size = (slot + slots_needed) * DIRENT_SZ;
+        if new_size > dir_inode.size() as usize {
+            // increase size
+            dir_inode.increase_size(new_size as u64);
+        }
+        // write dirent
+        let mut alloc_error = Error::NoSpace;
+        let written = dir_inode.write_at(
+            slot * DIRENT_SZ,
+            dirent.as_bytes(),
+            &self.block_device,
+            self.block_size,
+            self.checksums,
+            |_| fs.alloc_data().map_err(|err| alloc_error = err).ok(),
+        );
+        if written < dirent.as_bytes().len() {
+            // a partial dirent would be read as garbage, free its slots again
+            let zeros = vec![0u8; written];
+            dir_inode.write_at(slot * DIRENT_SZ, &zeros, &self.block_device, self.block_size, self.checksums, |_| None);
+            return Err(alloc_error);
+        }
+        if dir_inode.dir_index == 0 {
+            if fs.index_dirs() && dir_inode.size() > fs.block_size() as u64 {
+                self.build_dir_index(dir_inode, fs);
+            }
+        } else if let Err(err) = self.index_dirent(dir_inode, (name_hash(name), slot as u32), fs) {
+            let zeros = vec![0u8; written];
+            dir_inode.write_at(slot * DIRENT_SZ, &zeros, &self.block_device, self.block_size, self.checksums, |_| None);
+            return Err(err);
+        }
+        if dir_inode.dir_index != 0 {
+            dir_inode.dir_free_slot = if slot == first_free {
+                slot + slots_needed
+            } else {
+                first_free
+            } as u32;
+        }
+        Ok(())
+    }
+
+    /// Add `key` to the dirent index of `dir_inode`, allocating whatever
+    /// blocks the index needs for it up front.
+    fn index_dirent(
+        &self,
+        dir_inode: &mut DiskInode,
+        key: Key,
+        fs: &mut MutexGuard<EasyFileSystem>,
+    ) -> Result<()> {
+        let needed = dir_index::blocks_for_insert(
+            dir_inode.dir_index,
+            key,
+            &self.block_device,
+            self.block_size,
+            self.checksums,
+        )?;
+        let mut blocks: Vec<u32> = Vec::new();
+        while blocks.len() < needed {
+            match fs.alloc_data() {
+                Ok(block_id) => blocks.push(block_id),
+                Err(err) => {
+                    for block_id in blocks.into_iter() {
+                        fs.dealloc_data(block_id);
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        let inserted = dir_index::insert(
+            &mut dir_inode.dir_index,
+            key,
+            &mut blocks,
+            &self.block_device,
+            self.block_size,
+            self.checksums,
+        );
+        // blocks are only left over when a corrupt node stopped the insert
+        for block_id in blocks.into_iter() {
+            fs.dealloc_data(block_id);
+        }
+        inserted
+    }
+
+    /// Index every dirent of a directory that outgrew its first block. If
+    /// the index does not fit, or the dirents cannot be read, the directory
+    /// is left to be scanned.
+    fn build_dir_index(&self, dir_inode: &mut DiskInode, fs: &mut MutexGuard<EasyFileSystem>) {
+        let mut keys: Vec<Key> = Vec::new();
+        let walked = self.walk_dirents(dir_inode, |slot, dirent| {
+            match dirent.name() {
+                Ok(name) if !dirent.is_empty() => keys.push((name_hash(name), slot as u32)),
+                _ => {}
+            }
+            None::<()>
+        });
+        if walked.is_err() {
             return;
         }
-        let blocks_needed = disk_inode.blocks_num_needed(new_size);
-        let mut v: Vec<u32> = Vec::new();
-        for _ in 0..blocks_needed {
-            v.push(fs.alloc_data());
+        for key in keys.into_iter() {
+            if self.index_dirent(dir_inode, key, fs).is_err() {
+                dir_index::visit_nodes(dir_inode.dir_index, &self.block_device, self.checksums, &mut |block_id| {
+                    fs.dealloc_data(block_id);
+                    true
+                });
+                dir_inode.dir_index = 0;
+                return;
+            }
         }
-        disk_inode.increase_size(new_size, v, &self.block_device);
     }
 
-    pub fn create(&self, name: &str) -> Option<Arc<Inode>> {
-        let mut fs = self.fs.lock();
-        if self.modify_disk_inode(|root_inode| {
-            // assert it is a directory
-            assert!(root_inode.is_dir());
+    /// Allocate inode `name` and link it. What it changed is left for the
+    /// caller to commit, or to abort if it fails.
+    fn create_inode(
+        &self,
+        name: &str,
+        type_: DiskInodeType,
+        fs: &mut MutexGuard<EasyFileSystem>,
+    ) -> Result<Arc<Inode>> {
+        check_name(name)?;
+        self.read_disk_inode(|root_inode| {
+            if !root_inode.is_dir() {
+                return Err(Error::NotDir);
+            }
             // has the file been created?
-            self.find_inode_id(name, root_inode)
-        }).is_some() {
-            return None;
-        }
-        // create a new file
-        // alloc a inode with an indirect block
-        let new_inode_id = fs.alloc_inode();
+            match self.find_inode_id(name, root_inode)? {
+                Some(_) => Err(Error::Exists),
+                None => Ok(()),
+            }
+        })??;
+        let is_dir = type_ == DiskInodeType::Directory;
+        let inline = fs.inline_data() && !is_dir;
+        let now = fs.now();
+        // create a new inode
+        let new_inode_id = fs.alloc_inode()?;
         // initialize inode
-        let (new_inode_block_id, new_inode_block_offset) 
-            = fs.get_disk_inode_pos(new_inode_id);
-        get_block_cache(
-            new_inode_block_id as usize,
-            Arc::clone(&self.block_device)
-        ).lock().modify(new_inode_block_offset, |new_inode: &mut DiskInode| {
-            new_inode.initialize(DiskInodeType::File);
-        });
+        let new_inode = self.get_inode(new_inode_id, fs);
+        new_inode.modify_disk_inode(|new_inode| {
+            new_inode.initialize(type_, now);
+            if inline {
+                new_inode.set_inline();
+            }
+        })?;
+        // "." and ".." go first in every directory
+        if is_dir {
+            new_inode.modify_disk_inode(|dir_inode| {
+                new_inode.append_dirent(".", new_inode_id, dir_inode, fs)?;
+                new_inode.append_dirent("..", self.inode_id, dir_inode, fs)
+            })??;
+        }
         self.modify_disk_inode(|root_inode| {
-            // append file in the dirent
-            let file_count = (root_inode.size as usize) / DIRENT_SZ;
-            let new_size = (file_count + 1) * DIRENT_SZ;
-            // increase size
-            self.increase_size(new_size as u32, root_inode, &mut fs);
-            // write dirent
-            let dirent = DirEntry::new(name, new_inode_id);
-            root_inode.write_at(
-                file_count * DIRENT_SZ,
-                dirent.as_bytes(),
-                &self.block_device,
-            );
+            self.append_dirent(name, new_inode_id, root_inode, fs)
+        })??;
+        // return inode
+        Ok(new_inode)
+    }
+
+    pub fn create(&self, name: &str) -> Result<Arc<Inode>> {
+        let mut fs = self.fs.lock();
+        let inode = self.create_inode(name, DiskInodeType::File, &mut fs);
+        // a failed attempt is aborted, nothing of it reaches the disk
+        fs.finish(inode)
+    }
+
+    pub fn create_dir(&self, name: &str) -> Result<Arc<Inode>> {
+        let mut fs = self.fs.lock();
+        let inode = self.create_inode(name, DiskInodeType::Directory, &mut fs);
+        fs.finish(inode)
+    }
+
+    /// Create a symbolic link `name` pointing to `target`, which cannot be
+    /// empty. The link is committed along with its target.
+    pub fn symlink(&self, name: &str, target: &str) -> Result<Arc<Inode>> {
+        if target.is_empty() {
+            return Err(Error::InvalidName);
+        }
+        let mut fs = self.fs.lock();
+        let inode = self.create_inode(name, DiskInodeType::SymLink, &mut fs).and_then(|inode| {
+            // why the disk could not take the target, a corrupt bitmap or no space
+            let mut alloc_error = Error::NoSpace;
+            let written = inode.modify_disk_inode(|disk_inode| {
+                disk_inode.increase_size(target.len() as u64);
+                disk_inode.write_at(
+                    0,
+                    target.as_bytes(),
+                    &self.block_device,
+                    self.block_size,
+                    self.checksums,
+                    |_| fs.alloc_data().map_err(|err| alloc_error = err).ok(),
+                )
+            })?;
+            if written < target.len() {
+                return Err(alloc_error);
+            }
+            Ok(inode)
         });
+        fs.finish(inode)
+    }
 
-        let (block_id, block_offset) = fs.get_disk_inode_pos(new_inode_id);
-        // return inode
-        Some(Arc::new(Self::new(
-            block_id,
-            block_offset,
-            self.fs.clone(),
-            self.block_device.clone(),
-        )))
-        // release efs lock automatically by compiler
+    /// Return the target path if this inode is a symbolic link.
+    pub fn readlink(&self) -> Option<String> {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| {
+            if !disk_inode.is_symlink() {
+                return None;
+            }
+            let mut buf = vec![0u8; disk_inode.size() as usize];
+            disk_inode.read_at(0, &mut buf, &self.block_device, self.block_size);
+            String::from_utf8(buf).ok()
+        }).ok().flatten()
     }
 
-    pub fn ls(&self) -> Vec<String> {
+    pub fn ls(&self) -> Result<Vec<String>> {
         let _fs = self.fs.lock();
         self.read_disk_inode(|disk_inode| {
-            let file_count = (disk_inode.size as usize) / DIRENT_SZ;
             let mut v: Vec<String> = Vec::new();
-            for i in 0..file_count {
-                let mut dirent = DirEntry::empty();
-                assert_eq!(
-                    disk_inode.read_at(
-                        i * DIRENT_SZ,
-                        dirent.as_bytes_mut(),
-                        &self.block_device,
-                    ),
-                    DIRENT_SZ,
-                );
-                v.push(String::from(dirent.name()));
+            self.walk_dirents(disk_inode, |_, dirent| {
+                match dirent.name() {
+                    Ok(name) if !dirent.is_empty() => v.push(String::from(name)),
+                    _ => {}
+                }
+                None::<()>
+            })?;
+            Ok(v)
+        })?
+    }
+
+    /// Names and inode ids of every dirent in this directory.
+    pub(crate) fn dirents(&self) -> Result<Vec<(String, u32)>> {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| {
+            let mut v: Vec<(String, u32)> = Vec::new();
+            self.walk_dirents(disk_inode, |_, dirent| {
+                match dirent.name() {
+                    Ok(name) if !dirent.is_empty() => v.push((String::from(name), dirent.inode_number())),
+                    _ => {}
+                }
+                None::<()>
+            })?;
+            Ok(v)
+        })?
+    }
+
+    /// Whether the dirent index of this directory, if it has one, holds
+    /// the dirents and nothing else, with no free slot before `dir_free_slot`.
+    pub(crate) fn dir_index_is_valid(&self) -> bool {
+        let fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| {
+            if disk_inode.dir_index == 0 {
+                return true;
             }
-            v
+            let free_slot = disk_inode.dir_free_slot as usize;
+            let mut keys: Vec<Key> = Vec::new();
+            // checksum slots are never visited
+            let mut hint_valid = free_slot == (disk_inode.size() as usize) / DIRENT_SZ
+                || (fs.checksums() && is_checksum_slot(free_slot, fs.block_size()));
+            let free_before = self.walk_dirents(disk_inode, |slot, dirent| {
+                hint_valid |= slot == free_slot;
+                if !dirent.is_empty() {
+                    if let Ok(name) = dirent.name() {
+                        keys.push((name_hash(name), slot as u32));
+                    }
+                    None
+                } else if slot < free_slot {
+                    Some(())
+                } else {
+                    None
+                }
+            });
+            keys.sort_unstable();
+            let indexed = dir_index::keys(disk_inode.dir_index, &self.block_device, self.checksums)
+                .map(|mut indexed| {
+                    indexed.sort_unstable();
+                    indexed
+                });
+            hint_valid && free_before == Ok(None) && indexed == Ok(keys)
+        }) == Ok(true)
+    }
+
+    /// Whether a directory holds nothing but "." and "..".
+    fn dir_is_empty(&self, disk_inode: &DiskInode) -> Result<bool> {
+        let entry = self.walk_dirents(disk_inode, |_, dirent| {
+            if dirent.is_empty() || dirent.name() == Ok(".") || dirent.name() == Ok("..") {
+                None
+            } else {
+                Some(())
+            }
+        })?;
+        Ok(entry.is_none())
+    }
+
+    /// Fails unless this inode may go away through `unlink` or, if
+    /// `is_dir`, `rmdir`.
+    fn removable(&self, is_dir: bool) -> Result<()> {
+        self.read_disk_inode(|disk_inode| {
+            match (is_dir, disk_inode.is_dir()) {
+                (true, false) => Err(Error::NotDir),
+                (false, true) => Err(Error::IsDir),
+                (true, true) if !self.dir_is_empty(disk_inode)? => Err(Error::NotEmpty),
+                _ => Ok(()),
+            }
+        })?
+    }
+
+    /// Drop one link to this inode. It is reclaimed along with its data
+    /// blocks and attributes once no dirent refers to it any more.
+    fn drop_link(&self, fs: &mut MutexGuard<EasyFileSystem>) -> Result<()> {
+        let nlink = self.modify_disk_inode(|disk_inode| {
+            // a dirent led here, so there is a link to drop
+            disk_inode.nlink = disk_inode.nlink.checked_sub(1).ok_or(Error::Corrupt)?;
+            if disk_inode.nlink == 0 {
+                self.dealloc_blocks(disk_inode, fs);
+                if disk_inode.xattr_block != 0 {
+                    fs.dealloc_data(disk_inode.xattr_block);
+                    disk_inode.xattr_block = 0;
+                }
+            }
+            Ok(disk_inode.nlink)
+        }).and_then(|result| result)?;
+        if nlink == 0 {
+            fs.dealloc_inode(self.inode_id);
+        }
+        Ok(())
+    }
+
+    fn write_dirent(
+        &self,
+        slot: usize,
+        dirent: &DirEntry,
+        fs: &mut MutexGuard<EasyFileSystem>,
+    ) -> Result<()> {
+        self.modify_disk_inode(|dir_inode| {
+            dir_inode.write_at(
+                slot * DIRENT_SZ,
+                dirent.as_bytes(),
+                &self.block_device,
+                self.block_size,
+                self.checksums,
+                |_| fs.alloc_data().ok(),
+            );
         })
     }
 
-    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+    /// Free every slot `dirent` takes.
+    fn clear_dirent(
+        &self,
+        slot: usize,
+        dirent: &DirEntry,
+        fs: &mut MutexGuard<EasyFileSystem>,
+    ) -> Result<()> {
+        let key = (name_hash(dirent.name()?), slot as u32);
+        self.modify_disk_inode(|dir_inode| {
+            if dir_inode.dir_index != 0 {
+                dir_index::remove(dir_inode.dir_index, key, &self.block_device, self.checksums)?;
+                dir_inode.dir_free_slot = dir_inode.dir_free_slot.min(slot as u32);
+            }
+            Ok(())
+        }).and_then(|result| result)?;
+        let zeros = [0u8; DIRENT_SZ];
+        for i in slot..slot + dirent.slots() {
+            self.modify_disk_inode(|dir_inode| {
+                dir_inode.write_at(i * DIRENT_SZ, &zeros, &self.block_device, self.block_size, self.checksums, |_| fs.alloc_data().ok());
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Slot and dirent of `name` in this directory.
+    fn find_entry(&self, name: &str) -> Result<(usize, DirEntry)> {
+        self.read_disk_inode(|dir_inode| {
+            if !dir_inode.is_dir() {
+                return Err(Error::NotDir);
+            }
+            self.find_dirent(name, dir_inode)?.ok_or(Error::NotFound)
+        })?
+    }
+
+    fn remove(&self, name: &str, is_dir: bool) -> Result<()> {
+        if name == "." || name == ".." {
+            return Err(Error::InvalidName);
+        }
+        let mut fs = self.fs.lock();
+        let (slot, dirent) = self.find_entry(name)?;
+        let inode = self.get_inode(dirent.inode_number(), &fs);
+        inode.removable(is_dir)?;
+        let removed = inode.drop_link(&mut fs).and_then(|_| self.clear_dirent(slot, &dirent, &mut fs));
+        // a dirent whose inode is gone must not reach the disk
+        fs.finish(removed)
+    }
+
+    /// Add dirent `name` to this directory referring to the existing file `inode`.
+    pub fn link(&self, name: &str, inode: &Inode) -> Result<()> {
+        check_name(name)?;
+        if !Arc::ptr_eq(&self.fs, &inode.fs) {
+            return Err(Error::Unsupported);
+        }
+        let mut fs = self.fs.lock();
+        self.read_disk_inode(|dir_inode| {
+            if !dir_inode.is_dir() {
+                return Err(Error::NotDir);
+            }
+            match self.find_inode_id(name, dir_inode)? {
+                Some(_) => Err(Error::Exists),
+                None => Ok(()),
+            }
+        })??;
+        inode.read_disk_inode(|disk_inode| {
+            // hard links to directories are not allowed
+            if disk_inode.is_dir() {
+                return Err(Error::IsDir);
+            }
+            // a wrapped count would free the inode while it is still linked
+            if disk_inode.nlink == u16::MAX {
+                return Err(Error::TooManyLinks);
+            }
+            Ok(())
+        })??;
+        // the inode was just read, it passes its checks again
+        let linked = self.modify_disk_inode(|dir_inode| {
+            self.append_dirent(name, inode.inode_id, dir_inode, &mut fs)
+        }).and_then(|linked| linked).and_then(|_| inode.modify_disk_inode(|disk_inode| {
+            disk_inode.nlink += 1;
+        }));
+        fs.finish(linked)
+    }
+
+    /// Whether `ancestor_id` is this directory or one of its ancestors.
+    fn has_ancestor(&self, ancestor_id: u32, fs: &EasyFileSystem) -> Result<bool> {
+        let mut inode_id = self.inode_id;
+        loop {
+            if inode_id == ancestor_id {
+                return Ok(true);
+            }
+            if inode_id == 0 {
+                return Ok(false);
+            }
+            let dir = self.get_inode(inode_id, fs);
+            inode_id = dir.read_disk_inode(|disk_inode| {
+                dir.find_inode_id("..", disk_inode)
+            })??.ok_or(Error::Corrupt)?;
+        }
+    }
+
+    /// Move dirent `old_name` of this directory to `new_name` in `new_dir`,
+    /// replacing whatever `new_name` referred to before. A directory may only
+    /// replace an empty directory and a file may only replace a file.
+    pub fn rename(&self, old_name: &str, new_dir: &Inode, new_name: &str) -> Result<()> {
+        if [old_name, new_name].iter().any(|name| *name == "." || *name == "..") {
+            return Err(Error::InvalidName);
+        }
+        check_name(new_name)?;
+        if !Arc::ptr_eq(&self.fs, &new_dir.fs) {
+            return Err(Error::Unsupported);
+        }
+        let mut fs = self.fs.lock();
+        let (old_slot, old_dirent) = self.find_entry(old_name)?;
+        let inode_id = old_dirent.inode_number();
+        let inode = self.get_inode(inode_id, &fs);
+        let is_dir = inode.read_disk_inode(|disk_inode| disk_inode.is_dir())?;
+        let target = match new_dir.find_entry(new_name) {
+            Ok(target) => Some(target),
+            Err(Error::NotFound) => None,
+            Err(err) => return Err(err),
+        };
+        let moved = new_dir.inode_id != self.inode_id;
+        // a directory cannot be moved into its own subtree
+        if is_dir && moved && new_dir.has_ancestor(inode_id, &fs)? {
+            return Err(Error::InvalidName);
+        }
+        // the new dirent is written before the old one is cleared, so a crash
+        // in between leaves both names rather than none
+        let dirent = DirEntry::new(new_name, inode_id);
+        let added = match target {
+            Some((_, target)) if target.inode_number() == inode_id => return Ok(()),
+            Some((target_slot, target)) => {
+                let target = self.get_inode(target.inode_number(), &fs);
+                target.removable(is_dir)?;
+                // same name, hence the same slots
+                new_dir.write_dirent(target_slot, &dirent, &mut fs)
+                    .and_then(|_| target.drop_link(&mut fs))
+            }
+            None => new_dir.modify_disk_inode(|dir_inode| {
+                new_dir.append_dirent(new_name, inode_id, dir_inode, &mut fs)
+            }).and_then(|added| added),
+        };
+        let moved_dirent = added.and_then(|_| self.clear_dirent(old_slot, &old_dirent, &mut fs));
+        let renamed = if is_dir && moved {
+            moved_dirent.and_then(|_| inode.modify_disk_inode(|disk_inode| {
+                let (slot, _) = inode.find_dirent("..", disk_inode)?.ok_or(Error::Corrupt)?;
+                disk_inode.write_at(
+                    slot * DIRENT_SZ,
+                    DirEntry::new("..", new_dir.inode_id).as_bytes(),
+                    &self.block_device,
+                    self.block_size,
+                    self.checksums,
+                    |_| fs.alloc_data().ok(),
+                );
+                Ok(())
+            })).and_then(|renamed| renamed)
+        } else {
+            moved_dirent
+        };
+        // a rename that failed halfway is undone as a whole
+        fs.finish(renamed)
+    }
+
+    /// Remove a file from this directory.
+    pub fn unlink(&self, name: &str) -> Result<()> {
+        self.remove(name, false)
+    }
+
+    /// Remove an empty subdirectory from this directory.
+    pub fn rmdir(&self, name: &str) -> Result<()> {
+        self.remove(name, true)
+    }
+
+    pub fn stat(&self) -> Result<InodeStat> {
         let _fs = self.fs.lock();
         self.read_disk_inode(|disk_inode| {
-            disk_inode.read_at(offset, buf, &self.block_device)
+            InodeStat {
+                inode_id: self.inode_id,
+                type_: disk_inode.type_(),
+                size: disk_inode.size(),
+                nlink: disk_inode.nlink,
+                atime: disk_inode.atime,
+                mtime: disk_inode.mtime,
+                ctime: disk_inode.ctime,
+                uid: disk_inode.uid,
+                gid: disk_inode.gid,
+                mode: disk_inode.mode,
+                compressed: disk_inode.is_compressed(),
+            }
         })
     }
 
-    pub fn write_at(&self, offset: usize, buf: &[u8]) -> usize {
+    /// Compress the data of this empty file from now on, it is read and
+    /// written as ever. Fails with `Unsupported` on images older than
+    /// compression and for anything but an empty file.
+    pub fn set_compressed(&self) -> Result<()> {
+        let mut fs = self.fs.lock();
+        if !fs.compression() {
+            return Err(Error::Unsupported);
+        }
+        let result = self.modify_disk_inode(|disk_inode| {
+            if !disk_inode.is_file() || disk_inode.size() != 0 {
+                return Err(Error::Unsupported);
+            }
+            disk_inode.set_compressed();
+            Ok(())
+        }).and_then(|result| result);
+        fs.finish(result)
+    }
+
+    /// Set permission bits, anything beyond `0o777` is ignored.
+    pub fn set_mode(&self, mode: u16) -> Result<()> {
         let mut fs = self.fs.lock();
         self.modify_disk_inode(|disk_inode| {
-            self.increase_size((offset + buf.len()) as u32, disk_inode, &mut fs);
-            disk_inode.write_at(offset, buf, &self.block_device)
-        })
+            disk_inode.mode = mode & MODE_MASK;
+        })?;
+        fs.commit()
     }
 
-    pub fn clear(&self) {
+    pub fn set_owner(&self, uid: u32, gid: u32) -> Result<()> {
         let mut fs = self.fs.lock();
         self.modify_disk_inode(|disk_inode| {
-            let size = disk_inode.size;
-            let data_blocks_dealloc = disk_inode.clear_size(&self.block_device);
-            assert!(data_blocks_dealloc.len() == DiskInode::total_blocks(size) as usize);
-            for data_block in data_blocks_dealloc.into_iter() {
-                fs.dealloc_data(data_block);
+            disk_inode.uid = uid;
+            disk_inode.gid = gid;
+        })?;
+        fs.commit()
+    }
+
+    fn read_xattrs(&self, disk_inode: &DiskInode) -> Vec<(String, Vec<u8>)> {
+        if disk_inode.xattr_block == 0 {
+            return Vec::new();
+        }
+        get_block_cache(disk_inode.xattr_block as usize, Arc::clone(&self.block_device))
+            .lock()
+            .read_slice(0, |block: &DataBlock| parse_xattrs(block))
+    }
+
+    /// Replace the attributes of this inode by `attrs`. The attribute
+    /// block is allocated with the first attribute and freed with the last.
+    fn replace_xattrs(
+        &self,
+        disk_inode: &mut DiskInode,
+        attrs: &[(String, Vec<u8>)],
+        fs: &mut MutexGuard<EasyFileSystem>,
+    ) -> Result<()> {
+        if attrs.is_empty() {
+            if disk_inode.xattr_block != 0 {
+                fs.dealloc_data(disk_inode.xattr_block);
+                disk_inode.xattr_block = 0;
             }
-        });
+            return Ok(());
+        }
+        if xattrs_size(attrs) > fs.block_size() {
+            return Err(Error::NoSpace);
+        }
+        if disk_inode.xattr_block == 0 {
+            disk_inode.xattr_block = fs.alloc_data()?;
+        }
+        get_block_cache(disk_inode.xattr_block as usize, Arc::clone(&self.block_device))
+            .lock()
+            .modify_slice(0, |block: &mut DataBlock| write_xattrs(attrs, block));
+        Ok(())
     }
-}
+
+    /// Set extended attribute `name`, which all have to fit a block along
+    /// with their values.
+    pub fn set_xattr(&self, name: &str, value: &[u8]) -> Result<()> {
+        check_xattr_name(name)?;
+        let mut fs = self.fs.lock();
+        if !fs.xattrs() {
+            return Err(Error::Unsupported);
+        }
+        let result = self.modify_disk_inode(|disk_inode| {
+            let mut attrs = self.read_xattrs(disk_inode);
+            match attrs.iter_mut().find(|(attr_name, _)| attr_name == name) {
+                Some(attr) => attr.1 = value.to_vec(),
+                None => attrs.push((String::from(name), value.to_vec())),
+            }
+            self.replace_xattrs(disk_inode, &attrs, &mut fs)
+        }).and_then(|result| result);
+        fs.finish(result)
+    }
+
+    pub fn get_xattr(&self, name: &str) -> Result<Vec<u8>> {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| {
+            self.read_xattrs(disk_inode)
+                .into_iter()
+                .find(|(attr_name, _)| attr_name == name)
+                .map(|(_, value)| value)
+                .ok_or(Error::NotFound)
+        })?
+    }
+
+    pub fn list_xattr(&self) -> Result<Vec<String>> {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| {
+            self.read_xattrs(disk_inode)
+                .into_iter()
+                .map(|(name, _)| name)
+                .collect()
+        })
+    }
+
+    pub fn remove_xattr(&self, name: &str) -> Result<()> {
+        let mut fs = self.fs.lock();
+        let result = self.modify_disk_inode(|disk_inode| {
+            let mut attrs = self.read_xattrs(disk_inode);
+            let pos = attrs
+                .iter()
+                .position(|(attr_name, _)| attr_name == name)
+                .ok_or(Error::NotFound)?;
+            attrs.remove(pos);
+            self.replace_xattrs(disk_inode, &attrs, &mut fs)
+        }).and_then(|result| result);
+        fs.finish(result)
+    }
+
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let mut fs = self.fs.lock();
+        let now = fs.now();
+        let (read_size, touch) = self.read_disk_inode(|disk_inode| {
+            (
+                disk_inode.read_at(offset, buf, &self.block_device, self.block_size),
+                disk_inode.atime < disk_inode.mtime
+                    || now.saturating_sub(disk_inode.atime) >= ATIME_INTERVAL,
+            )
+        })?;
+        if touch {
+            self.modify_disk_inode(|disk_inode| {
+                disk_inode.atime = now;
+            })?;
+            // the data was read even if its atime cannot be recorded
+            let _ = fs.commit();
+        }
+        Ok(read_size)
+    }
+
+    /// Large writes are split into several transactions, a crash in the
+    /// middle leaves the file partially written but never inconsistent.
+    ///
+    /// Nothing is written past the largest file size the image supports,
+    /// nor once the image is full, so the count returned may fall short of
+    /// `buf.len()`. Only when nothing could be written is an error returned.
+    pub fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        let mut fs = self.fs.lock();
+        let max_size = fs.max_file_size() as usize;
+        if offset >= max_size && !buf.is_empty() {
+            return Err(Error::FileTooLarge);
+        }
+        let end = (offset + buf.len()).min(max_size);
+        if offset >= end {
+            return Ok(0);
+        }
+        // anything skipped past the end of file is left as a hole
+        let mut start = offset;
+        let mut write_size = 0usize;
+        let mut full = false;
+        // why the disk could not take more, a corrupt bitmap or no space
+        let mut alloc_error = Error::NoSpace;
+        let block_size = fs.block_size();
+        loop {
+            let chunk_end = end.min((start / block_size + WRITE_TXN_BLOCKS) * block_size);
+            let chunk_size = self.modify_disk_inode(|disk_inode| {
+                let old_size = disk_inode.size();
+                if chunk_end > old_size as usize {
+                    disk_inode.increase_size(chunk_end as u64);
+                }
+                // holes of the chunk are filled from one run of blocks
+                // where the disk allows, next to the blocks before them
+                let mut run = (0u32, 0u32);
+                let chunk_size = disk_inode.write_at(
+                    start,
+                    &buf[start - offset..chunk_end - offset],
+                    &self.block_device,
+                    self.block_size,
+                    self.checksums,
+                    |goal| {
+                        if run.1 == 0 {
+                            let wanted = (chunk_end - 1) / block_size - start / block_size + 1;
+                            run = fs.alloc_data_range(wanted, goal)
+                                .map_err(|err| alloc_error = err)
+                                .ok()?;
+                        }
+                        run.0 += 1;
+                        run.1 -= 1;
+                        Some(run.0 - 1)
+                    },
+                );
+                // blocks of the run left over, where the chunk had no holes
+                for block_id in run.0..run.0 + run.1 {
+                    fs.dealloc_data(block_id);
+                }
+                if chunk_size < chunk_end - start {
+                    // out of space, the file only grows as far as it was written
+                    full = true;
+                    let kept_size = old_size.max((start + chunk_size) as u64);
+                    if kept_size < disk_inode.size() {
+                        for block_id in disk_inode.decrease_size(kept_size, &self.block_device, self.block_size, self.checksums) {
+                            fs.dealloc_data(block_id);
+                        }
+                    }
+                }
+                disk_inode.mtime = fs.now();
+                chunk_size
+            })?;
+            // a chunk the journal cannot take is dropped like one the disk
+            // has no room for
+            if let Err(err) = fs.commit() {
+                alloc_error = err;
+                full = true;
+                break;
+            }
+            write_size += chunk_size;
+            if full || chunk_end == end {
+                break;
+            }
+            start = chunk_end;
+        }
+        if full && write_size == 0 {
+            return Err(alloc_error);
+        }
+        Ok(write_size)
+    }
+
+    /// Flush this inode to disk. Changes are committed as a whole, so
+    /// anything else pending is flushed along with it.
+    pub fn sync(&self) {
+        self.fs.lock().sync_all();
+    }
+
+    fn dealloc_blocks(
+        &self,
+        disk_inode: &mut DiskInode,
+        fs: &mut MutexGuard<EasyFileSystem>,
+    ) {
+        let data_blocks_dealloc = disk_inode.clear_size(&self.block_device, self.block_size, self.checksums);
+        for data_block in data_blocks_dealloc.into_iter() {
+            fs.dealloc_data(data_block);
+        }
+    }
+
+    /// Drop all data of this file. Directories and symbolic links cannot
+    /// be cleared, they would lose their dirents or target.
+    pub fn clear(&self) -> Result<()> {
+        let mut fs = self.fs.lock();
+        let result = self.modify_disk_inode(|disk_inode| {
+            if !disk_inode.is_file() {
+                return Err(Error::NotFile);
+            }
+            self.dealloc_blocks(disk_inode, &mut fs);
+            disk_inode.mtime = fs.now();
+            Ok(())
+        }).and_then(|result| result);
+        fs.finish(result)
+    }
+
+    /// Shrink the file to `new_size` bytes, freeing the blocks past it, or
+    /// extend it with a hole that reads back as zeros. Fails with
+    /// `FileTooLarge` if `new_size` is past the largest file size the image
+    /// supports, and with `NotFile` for anything but a regular file.
+    pub fn truncate(&self, new_size: usize) -> Result<()> {
+        let mut fs = self.fs.lock();
+        if new_size as u64 > fs.max_file_size() {
+            return Err(Error::FileTooLarge);
+        }
+        let truncated = self.modify_disk_inode(|disk_inode| {
+            if !disk_inode.is_file() {
+                return Err(Error::NotFile);
+            }
+            if new_size >= disk_inode.size() as usize {
+                disk_inode.increase_size(new_size as u64);
+            } else {
+                let blocks = disk_inode.decrease_size(new_size as u64, &self.block_device, self.block_size, self.checksums);
+                for block_id in blocks.into_iter() {
+                    fs.dealloc_data(block_id);
+                }
+            }
+            disk_inode.mtime = fs.now();
+            Ok(())
+        }).and_then(|result| result);
+        fs.finish(truncated)
+    }
+}
\ No newline at end of file
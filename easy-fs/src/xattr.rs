@@ -0,0 +1,55 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// Longest name of an extended attribute.
+pub const XATTR_NAME_LIMIT: usize = 255;
+/// An entry starts with the length of its name in a byte and the length
+/// of its value in two, followed by the name and the value.
+const XATTR_HEADER_SZ: usize = 3;
+
+/// Extended attributes of an inode as kept in its attribute block. The
+/// entries follow each other, the first one with an empty name ends them.
+pub fn parse_xattrs(block: &[u8]) -> Vec<(String, Vec<u8>)> {
+    let mut attrs: Vec<(String, Vec<u8>)> = Vec::new();
+    let mut pos = 0usize;
+    while pos + XATTR_HEADER_SZ <= block.len() {
+        let name_len = block[pos] as usize;
+        let value_len = u16::from_le_bytes([block[pos + 1], block[pos + 2]]) as usize;
+        let name_start = pos + XATTR_HEADER_SZ;
+        let value_start = name_start + name_len;
+        if name_len == 0 || value_start + value_len > block.len() {
+            break;
+        }
+        let name = match core::str::from_utf8(&block[name_start..value_start]) {
+            Ok(name) => String::from(name),
+            Err(_) => break,
+        };
+        attrs.push((name, block[value_start..value_start + value_len].to_vec()));
+        pos = value_start + value_len;
+    }
+    attrs
+}
+
+/// Bytes `attrs` take in an attribute block.
+pub fn xattrs_size(attrs: &[(String, Vec<u8>)]) -> usize {
+    attrs
+        .iter()
+        .map(|(name, value)| XATTR_HEADER_SZ + name.len() + value.len())
+        .sum()
+}
+
+/// Write `attrs` to an attribute block, which they must fit.
+pub fn write_xattrs(attrs: &[(String, Vec<u8>)], block: &mut [u8]) {
+    assert!(xattrs_size(attrs) <= block.len());
+    let mut pos = 0usize;
+    for (name, value) in attrs.iter() {
+        block[pos] = name.len() as u8;
+        block[pos + 1..pos + XATTR_HEADER_SZ].copy_from_slice(&(value.len() as u16).to_le_bytes());
+        pos += XATTR_HEADER_SZ;
+        block[pos..pos + name.len()].copy_from_slice(name.as_bytes());
+        pos += name.len();
+        block[pos..pos + value.len()].copy_from_slice(value);
+        pos += value.len();
+    }
+    block[pos..].iter_mut().for_each(|byte| *byte = 0);
+}
@@ -1,5 +1,6 @@
 use easy_fs::{
     EasyFileSystem,
+    DiskInodeType,
     Inode,
 };
 use crate::drivers::BLOCK_DEVICE;
@@ -8,8 +9,10 @@ use lazy_static::*;
 use bitflags::*;
 use alloc::vec::Vec;
 use spin::Mutex;
-use super::File;
+use super::{File, Stat, StatFs, StatMode};
 use crate::mm::UserBuffer;
+use crate::timer::get_time_ms;
+use crate::config::BLOCK_CACHE_SIZE;
 
 pub struct OSInode {
     readable: bool,
@@ -42,7 +45,10 @@ impl OSInode {
         let mut buffer = [0u8; 512];
         let mut v: Vec<u8> = Vec::new();
         loop {
-            let len = inner.inode.read_at(inner.offset, &mut buffer);
+            let len = match inner.inode.read_at(inner.offset, &mut buffer) {
+                Ok(len) => len,
+                Err(_) => break,
+            };
             if len == 0 {
                 break;
             }
@@ -51,18 +57,44 @@ impl OSInode {
         }
         v
     }
+    pub fn executable(&self) -> bool {
+        self.inner.lock().inode.stat().map_or(false, |stat| {
+            StatMode::from_bits_truncate(stat.mode as u32).contains(StatMode::IXUSR)
+        })
+    }
 }
 
 lazy_static! {
-    pub static ref ROOT_INODE: Arc<Inode> = {
-        let efs = EasyFileSystem::open(BLOCK_DEVICE.clone());
-        Arc::new(EasyFileSystem::root_inode(&efs))
-    };
+    pub static ref EFS: Arc<Mutex<EasyFileSystem>> = EasyFileSystem::open(
+        BLOCK_DEVICE.clone(),
+        || get_time_ms() as u64,
+        BLOCK_CACHE_SIZE,
+    ).expect("Error loading EFS!");
+    pub static ref ROOT_INODE: Arc<Inode> = Arc::new(EasyFileSystem::root_inode(&EFS));
+}
+
+/// Flush the file system before shutting down. Skipped while an operation
+/// holds it, e.g. one interrupted by a panic, as its changes are half done.
+pub fn sync_all() {
+    if let Some(mut efs) = EFS.try_lock() {
+        efs.sync_all();
+    }
+}
+
+pub fn stat_fs() -> StatFs {
+    let stat = EFS.lock().stat_fs();
+    StatFs {
+        bsize: stat.block_size as u64,
+        blocks: stat.total_blocks as u64,
+        bfree: stat.free_blocks as u64,
+        files: stat.total_inodes as u64,
+        ffree: stat.free_inodes as u64,
+    }
 }
 
 pub fn list_apps() {
     println!("/**** APPS ****");
-    for app in ROOT_INODE.ls() {
+    for app in ROOT_INODE.ls().unwrap_or_default() {
         println!("{}", app);
     }
     println!("**************/")
@@ -92,20 +124,81 @@ impl OpenFlags {
     }
 }
 
-pub fn open_file(name: &str, flags: OpenFlags) -> Option<Arc<OSInode>> {
+/// Symbolic links followed during one lookup before giving up.
+const SYMLINK_FOLLOW_LIMIT: usize = 8;
+
+/// Resolve `path` relative to `dir`, following symbolic links.
+fn lookup_from(dir: &Arc<Inode>, path: &str, follows: &mut usize) -> Option<Arc<Inode>> {
+    let mut inode = if path.starts_with('/') {
+        ROOT_INODE.clone()
+    } else {
+        dir.clone()
+    };
+    for name in path.split('/').filter(|name| !name.is_empty()) {
+        if !inode.is_dir() {
+            return None;
+        }
+        let parent = inode;
+        inode = parent.find(name).ok()?;
+        if let Some(target) = inode.readlink() {
+            *follows += 1;
+            if *follows > SYMLINK_FOLLOW_LIMIT {
+                return None;
+            }
+            // a relative target is resolved from the directory holding the link
+            inode = lookup_from(&parent, target.as_str(), follows)?;
+        }
+    }
+    Some(inode)
+}
+
+fn lookup(path: &str) -> Option<Arc<Inode>> {
+    let mut follows = 0usize;
+    lookup_from(&ROOT_INODE, path, &mut follows)
+}
+
+/// Split `path` into its parent directory and the last component.
+fn split_path(path: &str) -> (&str, &str) {
+    match path.rfind('/') {
+        Some(0) => ("/", &path[1..]),
+        Some(idx) => (&path[..idx], &path[idx + 1..]),
+        None => ("", path),
+    }
+}
+
+/// There are no users yet, so every process is checked against the owner bits.
+fn permitted(inode: &Inode, readable: bool, writable: bool) -> bool {
+    inode.stat().map_or(false, |stat| {
+        let mode = StatMode::from_bits_truncate(stat.mode as u32);
+        (!readable || mode.contains(StatMode::IRUSR))
+            && (!writable || mode.contains(StatMode::IWUSR))
+    })
+}
+
+pub fn open_file(path: &str, flags: OpenFlags) -> Option<Arc<OSInode>> {
     let (readable, writable) = flags.read_write();
     if flags.contains(OpenFlags::CREATE) {
-        if let Some(inode) = ROOT_INODE.find(name) {
+        if let Some(inode) = lookup(path) {
+            // directories cannot be truncated
+            if inode.is_dir() || !permitted(&inode, readable, true) {
+                return None;
+            }
             // clear size
-            inode.clear();
+            inode.clear().ok()?;
             Some(Arc::new(OSInode::new(
                 readable,
                 writable,
                 inode,
             )))
         } else {
-            // create file
-            ROOT_INODE.create(name)
+            // create file in its parent directory
+            let (parent_path, name) = split_path(path);
+            if name.is_empty() {
+                return None;
+            }
+            lookup(parent_path)
+                .filter(|parent| parent.is_dir())
+                .and_then(|parent| parent.create(name).ok())
                 .map(|inode| {
                     Arc::new(OSInode::new(
                         readable,
@@ -115,11 +208,14 @@ pub fn open_file(name: &str, flags: OpenFlags) -> Option<Arc<OSInode>> {
                 })
         }
     } else {
-        ROOT_INODE.find(name)
+        // directories can neither be written nor truncated
+        lookup(path)
+            .filter(|inode| !(inode.is_dir() && (writable || flags.contains(OpenFlags::TRUNC))))
+            .filter(|inode| {
+                permitted(inode, readable, writable || flags.contains(OpenFlags::TRUNC))
+            })
+            .filter(|inode| !flags.contains(OpenFlags::TRUNC) || inode.clear().is_ok())
             .map(|inode| {
-                if flags.contains(OpenFlags::TRUNC) {
-                    inode.clear();
-                }
                 Arc::new(OSInode::new(
                     readable,
                     writable,
@@ -129,6 +225,21 @@ pub fn open_file(name: &str, flags: OpenFlags) -> Option<Arc<OSInode>> {
     }
 }
 
+/// Rename `old_path` to `new_path`, replacing the entry at `new_path`.
+pub fn rename_file(old_path: &str, new_path: &str) -> bool {
+    let (old_parent_path, old_name) = split_path(old_path);
+    let (new_parent_path, new_name) = split_path(new_path);
+    if old_name.is_empty() || new_name.is_empty() {
+        return false;
+    }
+    match (lookup(old_parent_path), lookup(new_parent_path)) {
+        (Some(old_parent), Some(new_parent)) => {
+            old_parent.rename(old_name, &new_parent, new_name).is_ok()
+        }
+        _ => false,
+    }
+}
+
 impl File for OSInode {
     fn readable(&self) -> bool { self.readable }
     fn writable(&self) -> bool { self.writable }
@@ -136,7 +247,10 @@ impl File for OSInode {
         let mut inner = self.inner.lock();
         let mut total_read_size = 0usize;
         for slice in buf.buffers.iter_mut() {
-            let read_size = inner.inode.read_at(inner.offset, *slice);
+            let read_size = match inner.inode.read_at(inner.offset, *slice) {
+                Ok(read_size) => read_size,
+                Err(_) => break,
+            };
             if read_size == 0 {
                 break;
             }
@@ -149,11 +263,51 @@ impl File for OSInode {
         let mut inner = self.inner.lock();
         let mut total_write_size = 0usize;
         for slice in buf.buffers.iter() {
-            let write_size = inner.inode.write_at(inner.offset, *slice);
-            assert_eq!(write_size, slice.len());
+            let write_size = match inner.inode.write_at(inner.offset, *slice) {
+                Ok(write_size) => write_size,
+                Err(_) => break,
+            };
             inner.offset += write_size;
             total_write_size += write_size;
+            // the file or the disk cannot take any more
+            if write_size < slice.len() {
+                break;
+            }
         }
         total_write_size
     }
+    fn sync(&self) {
+        self.inner.lock().inode.sync();
+    }
+    fn truncate(&self, length: usize) -> bool {
+        if !self.writable {
+            return false;
+        }
+        self.inner.lock().inode.truncate(length).is_ok()
+    }
+    fn get_xattr(&self, name: &str) -> Option<Vec<u8>> {
+        self.inner.lock().inode.get_xattr(name).ok()
+    }
+    fn set_xattr(&self, name: &str, value: &[u8]) -> bool {
+        self.inner.lock().inode.set_xattr(name, value).is_ok()
+    }
+    fn stat(&self) -> Option<Stat> {
+        let stat = self.inner.lock().inode.stat().ok()?;
+        let mode = match stat.type_ {
+            DiskInodeType::File => StatMode::FILE,
+            DiskInodeType::Directory => StatMode::DIR,
+            DiskInodeType::SymLink => StatMode::LNK,
+        };
+        Some(Stat {
+            ino: stat.inode_id as u64,
+            mode: mode | StatMode::from_bits_truncate(stat.mode as u32),
+            nlink: stat.nlink as u32,
+            uid: stat.uid,
+            gid: stat.gid,
+            size: stat.size,
+            atime: stat.atime,
+            mtime: stat.mtime,
+            ctime: stat.ctime,
+        })
+    }
 }
\ No newline at end of file
@@ -3,14 +3,77 @@ mod stdio;
 mod inode;
 
 use crate::mm::UserBuffer;
+use alloc::vec::Vec;
 
 pub trait File : Send + Sync {
     fn readable(&self) -> bool;
     fn writable(&self) -> bool;
     fn read(&self, buf: UserBuffer) -> usize;
     fn write(&self, buf: UserBuffer) -> usize;
+    /// Only files backed by an inode have attributes.
+    fn stat(&self) -> Option<Stat> { None }
+    /// Write back whatever is cached for this file.
+    fn sync(&self) {}
+    /// Set the length of a file, only files backed by an inode have one.
+    fn truncate(&self, _length: usize) -> bool { false }
+    /// Extended attributes, only files backed by an inode have them.
+    fn get_xattr(&self, _name: &str) -> Option<Vec<u8>> { None }
+    fn set_xattr(&self, _name: &str, _value: &[u8]) -> bool { false }
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct Stat {
+    /// inode number
+    pub ino: u64,
+    /// file type and permission bits
+    pub mode: StatMode,
+    /// number of hard links
+    pub nlink: u32,
+    /// owner and group ids
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u64,
+    /// last access, last modification and creation time in milliseconds
+    pub atime: u64,
+    pub mtime: u64,
+    pub ctime: u64,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct StatFs {
+    /// block size in bytes
+    pub bsize: u64,
+    /// data blocks in total and free
+    pub blocks: u64,
+    pub bfree: u64,
+    /// inodes in total and free
+    pub files: u64,
+    pub ffree: u64,
+}
+
+bitflags! {
+    pub struct StatMode: u32 {
+        const NULL = 0;
+        const DIR  = 0o040000;
+        const FILE = 0o100000;
+        const LNK  = 0o120000;
+        /// owner
+        const IRUSR = 0o400;
+        const IWUSR = 0o200;
+        const IXUSR = 0o100;
+        /// group
+        const IRGRP = 0o040;
+        const IWGRP = 0o020;
+        const IXGRP = 0o010;
+        /// others
+        const IROTH = 0o004;
+        const IWOTH = 0o002;
+        const IXOTH = 0o001;
+    }
 }
 
 pub use pipe::{Pipe, make_pipe};
 pub use stdio::{Stdin, Stdout};
-pub use inode::{OSInode, open_file, OpenFlags, list_apps};
\ No newline at end of file
+pub use inode::{OSInode, open_file, rename_file, sync_all, stat_fs, OpenFlags, list_apps, ROOT_INODE};
\ No newline at end of file
@@ -37,6 +37,7 @@ pub fn console_getchar() -> usize {
 }
 
 pub fn shutdown() -> ! {
+    crate::fs::sync_all();
     sbi_call(SBI_SHUTDOWN, 0, 0, 0);
     panic!("It should shutdown!");
 }
@@ -5,8 +5,9 @@ use crate::mm::{
     translated_str,
 };
 use crate::task::{current_user_token, current_task};
-use crate::fs::{make_pipe, OpenFlags, open_file};
+use crate::fs::{make_pipe, OpenFlags, open_file, rename_file, stat_fs, Stat, StatFs, ROOT_INODE};
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 
 pub fn sys_write(fd: usize, buf: *const u8, len: usize) -> isize {
     let token = current_user_token();
@@ -69,6 +70,17 @@ pub fn sys_open(path: *const u8, flags: u32) -> isize {
     }
 }
 
+pub fn sys_renameat(old_path: *const u8, new_path: *const u8) -> isize {
+    let token = current_user_token();
+    let old_path = translated_str(token, old_path);
+    let new_path = translated_str(token, new_path);
+    if rename_file(old_path.as_str(), new_path.as_str()) {
+        0
+    } else {
+        -1
+    }
+}
+
 pub fn sys_close(fd: usize) -> isize {
     let task = current_task().unwrap();
     let mut inner = task.acquire_inner_lock();
@@ -82,6 +94,142 @@ pub fn sys_close(fd: usize) -> isize {
     0
 }
 
+pub fn sys_fstat(fd: usize, st: *mut Stat) -> isize {
+    let token = current_user_token();
+    let task = current_task().unwrap();
+    let inner = task.acquire_inner_lock();
+    if fd >= inner.fd_table.len() {
+        return -1;
+    }
+    if let Some(file) = &inner.fd_table[fd] {
+        let file = file.clone();
+        // release Task lock manually to avoid deadlock
+        drop(inner);
+        if let Some(stat) = file.stat() {
+            let size = core::mem::size_of::<Stat>();
+            let bytes = unsafe {
+                core::slice::from_raw_parts(&stat as *const _ as *const u8, size)
+            };
+            let user_buf = UserBuffer::new(translated_byte_buffer(token, st as *const u8, size));
+            for (byte_ref, byte) in user_buf.into_iter().zip(bytes.iter()) {
+                unsafe { *byte_ref = *byte; }
+            }
+            0
+        } else {
+            -1
+        }
+    } else {
+        -1
+    }
+}
+
+pub fn sys_statfs(st: *mut StatFs) -> isize {
+    let token = current_user_token();
+    let stat = stat_fs();
+    let size = core::mem::size_of::<StatFs>();
+    let bytes = unsafe {
+        core::slice::from_raw_parts(&stat as *const _ as *const u8, size)
+    };
+    let user_buf = UserBuffer::new(translated_byte_buffer(token, st as *const u8, size));
+    for (byte_ref, byte) in user_buf.into_iter().zip(bytes.iter()) {
+        unsafe { *byte_ref = *byte; }
+    }
+    0
+}
+
+pub fn sys_sync() -> isize {
+    ROOT_INODE.sync();
+    0
+}
+
+pub fn sys_fsync(fd: usize) -> isize {
+    let task = current_task().unwrap();
+    let inner = task.acquire_inner_lock();
+    if fd >= inner.fd_table.len() {
+        return -1;
+    }
+    if let Some(file) = &inner.fd_table[fd] {
+        let file = file.clone();
+        // release Task lock manually to avoid deadlock
+        drop(inner);
+        file.sync();
+        0
+    } else {
+        -1
+    }
+}
+
+pub fn sys_ftruncate(fd: usize, length: usize) -> isize {
+    let task = current_task().unwrap();
+    let inner = task.acquire_inner_lock();
+    if fd >= inner.fd_table.len() {
+        return -1;
+    }
+    if let Some(file) = &inner.fd_table[fd] {
+        if !file.writable() {
+            return -1;
+        }
+        let file = file.clone();
+        // release Task lock manually to avoid deadlock
+        drop(inner);
+        if file.truncate(length) { 0 } else { -1 }
+    } else {
+        -1
+    }
+}
+
+pub fn sys_fsetxattr(fd: usize, name: *const u8, value: *const u8, len: usize) -> isize {
+    let token = current_user_token();
+    let task = current_task().unwrap();
+    let inner = task.acquire_inner_lock();
+    if fd >= inner.fd_table.len() {
+        return -1;
+    }
+    if let Some(file) = &inner.fd_table[fd] {
+        let file = file.clone();
+        // release Task lock manually to avoid deadlock
+        drop(inner);
+        let name = translated_str(token, name);
+        let value: Vec<u8> = translated_byte_buffer(token, value, len)
+            .into_iter()
+            .flat_map(|slice| slice.iter().copied())
+            .collect();
+        if file.set_xattr(name.as_str(), &value) { 0 } else { -1 }
+    } else {
+        -1
+    }
+}
+
+/// Give the length of the attribute, copying it to `value` unless `len`
+/// is 0. A buffer too small for it is an error.
+pub fn sys_fgetxattr(fd: usize, name: *const u8, value: *mut u8, len: usize) -> isize {
+    let token = current_user_token();
+    let task = current_task().unwrap();
+    let inner = task.acquire_inner_lock();
+    if fd >= inner.fd_table.len() {
+        return -1;
+    }
+    if let Some(file) = &inner.fd_table[fd] {
+        let file = file.clone();
+        // release Task lock manually to avoid deadlock
+        drop(inner);
+        let name = translated_str(token, name);
+        match file.get_xattr(name.as_str()) {
+            Some(attr) if len == 0 => attr.len() as isize,
+            Some(attr) if attr.len() <= len => {
+                let user_buf = UserBuffer::new(translated_byte_buffer(token, value as *const u8, attr.len()));
+                for (byte_ref, byte) in user_buf.into_iter().zip(attr.iter()) {
+                    unsafe { *byte_ref = *byte; }
+                }
+                attr.len() as isize
+            }
+            _ => -1,
+        }
+    } else {
+        -1
+    }
+}
+
 pub fn sys_pipe(pipe: *mut usize) -> isize {
     let task = current_task().unwrap();
     let token = current_user_token();
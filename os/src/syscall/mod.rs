@@ -1,9 +1,17 @@
+const SYSCALL_FSETXATTR: usize = 7;
+const SYSCALL_FGETXATTR: usize = 10;
 const SYSCALL_DUP: usize = 24;
+const SYSCALL_RENAMEAT: usize = 38;
+const SYSCALL_STATFS: usize = 43;
+const SYSCALL_FTRUNCATE: usize = 46;
 const SYSCALL_OPEN: usize = 56;
 const SYSCALL_CLOSE: usize = 57;
 const SYSCALL_PIPE: usize = 59;
 const SYSCALL_READ: usize = 63;
 const SYSCALL_WRITE: usize = 64;
+const SYSCALL_FSTAT: usize = 80;
+const SYSCALL_SYNC: usize = 81;
+const SYSCALL_FSYNC: usize = 82;
 const SYSCALL_EXIT: usize = 93;
 const SYSCALL_YIELD: usize = 124;
 const SYSCALL_GET_TIME: usize = 169;
@@ -17,15 +25,24 @@ mod process;
 
 use fs::*;
 use process::*;
+use crate::fs::{Stat, StatFs};
 
-pub fn syscall(syscall_id: usize, args: [usize; 3]) -> isize {
+pub fn syscall(syscall_id: usize, args: [usize; 4]) -> isize {
     match syscall_id {
+        SYSCALL_FSETXATTR => sys_fsetxattr(args[0], args[1] as *const u8, args[2] as *const u8, args[3]),
+        SYSCALL_FGETXATTR => sys_fgetxattr(args[0], args[1] as *const u8, args[2] as *mut u8, args[3]),
         SYSCALL_DUP=> sys_dup(args[0]),
+        SYSCALL_RENAMEAT => sys_renameat(args[0] as *const u8, args[1] as *const u8),
+        SYSCALL_STATFS => sys_statfs(args[0] as *mut StatFs),
+        SYSCALL_FTRUNCATE => sys_ftruncate(args[0], args[1]),
         SYSCALL_OPEN => sys_open(args[0] as *const u8, args[1] as u32),
         SYSCALL_CLOSE => sys_close(args[0]),
         SYSCALL_PIPE => sys_pipe(args[0] as *mut usize),
         SYSCALL_READ => sys_read(args[0], args[1] as *const u8, args[2]),
         SYSCALL_WRITE => sys_write(args[0], args[1] as *const u8, args[2]),
+        SYSCALL_FSTAT => sys_fstat(args[0], args[1] as *mut Stat),
+        SYSCALL_SYNC => sys_sync(),
+        SYSCALL_FSYNC => sys_fsync(args[0]),
         SYSCALL_EXIT => sys_exit(args[0] as i32),
         SYSCALL_YIELD => sys_yield(),
         SYSCALL_GET_TIME => sys_get_time(),
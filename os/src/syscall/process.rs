@@ -64,6 +64,9 @@ pub fn sys_exec(path: *const u8, mut args: *const usize) -> isize {
         unsafe { args = args.add(1); }
     }
     if let Some(app_inode) = open_file(path.as_str(), OpenFlags::RDONLY) {
+        if !app_inode.executable() {
+            return -1;
+        }
         let all_data = app_inode.read_all();
         let task = current_task().unwrap();
         let argc = args_vec.len();
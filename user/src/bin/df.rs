@@ -0,0 +1,58 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{statfs, StatFs};
+
+/// Usage at or above this percentage makes `df` exit with 1.
+const DEFAULT_THRESHOLD: u64 = 90;
+
+fn percent(used: u64, total: u64) -> u64 {
+    if total == 0 { 0 } else { (used * 100 + total - 1) / total }
+}
+
+/// Usage: df [threshold]
+#[no_mangle]
+pub fn main(argc: usize, argv: &[&str]) -> i32 {
+    let threshold = if argc > 1 {
+        match argv[1].parse::<u64>() {
+            Ok(threshold) => threshold,
+            Err(_) => {
+                println!("df: invalid threshold {}", argv[1]);
+                return -1;
+            }
+        }
+    } else {
+        DEFAULT_THRESHOLD
+    };
+    let mut st = StatFs::new();
+    if statfs(&mut st) != 0 {
+        println!("df: statfs failed");
+        return -1;
+    }
+    let used_blocks = st.blocks - st.bfree;
+    let used_inodes = st.files - st.ffree;
+    let block_usage = percent(used_blocks, st.blocks);
+    let inode_usage = percent(used_inodes, st.files);
+    println!("{:>10} {:>10} {:>10} {:>5}", "KiB", "used", "avail", "use%");
+    println!(
+        "{:>10} {:>10} {:>10} {:>4}%",
+        st.blocks * st.bsize / 1024,
+        used_blocks * st.bsize / 1024,
+        st.bfree * st.bsize / 1024,
+        block_usage,
+    );
+    println!("{:>10} {:>10} {:>10} {:>5}", "inodes", "used", "free", "use%");
+    println!(
+        "{:>10} {:>10} {:>10} {:>4}%",
+        st.files, used_inodes, st.ffree, inode_usage,
+    );
+    if block_usage >= threshold || inode_usage >= threshold {
+        println!("df: file system is at least {}% full", threshold);
+        1
+    } else {
+        0
+    }
+}
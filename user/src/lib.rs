@@ -69,12 +69,101 @@ bitflags! {
     }
 }
 
+#[repr(C)]
+#[derive(Debug)]
+pub struct Stat {
+    /// inode number
+    pub ino: u64,
+    /// file type and permission bits
+    pub mode: StatMode,
+    /// number of hard links
+    pub nlink: u32,
+    /// owner and group ids
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u64,
+    /// last access, last modification and creation time in milliseconds
+    pub atime: u64,
+    pub mtime: u64,
+    pub ctime: u64,
+}
+
+impl Stat {
+    pub fn new() -> Self {
+        Stat {
+            ino: 0,
+            mode: StatMode::NULL,
+            nlink: 0,
+            uid: 0,
+            gid: 0,
+            size: 0,
+            atime: 0,
+            mtime: 0,
+            ctime: 0,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct StatFs {
+    /// block size in bytes
+    pub bsize: u64,
+    /// data blocks in total and free
+    pub blocks: u64,
+    pub bfree: u64,
+    /// inodes in total and free
+    pub files: u64,
+    pub ffree: u64,
+}
+
+impl StatFs {
+    pub fn new() -> Self {
+        StatFs {
+            bsize: 0,
+            blocks: 0,
+            bfree: 0,
+            files: 0,
+            ffree: 0,
+        }
+    }
+}
+
+bitflags! {
+    pub struct StatMode: u32 {
+        const NULL = 0;
+        const DIR  = 0o040000;
+        const FILE = 0o100000;
+        const LNK  = 0o120000;
+        /// owner
+        const IRUSR = 0o400;
+        const IWUSR = 0o200;
+        const IXUSR = 0o100;
+        /// group
+        const IRGRP = 0o040;
+        const IWGRP = 0o020;
+        const IXGRP = 0o010;
+        /// others
+        const IROTH = 0o004;
+        const IWOTH = 0o002;
+        const IXOTH = 0o001;
+    }
+}
+
 pub fn dup(fd: usize) -> isize { sys_dup(fd) }
 pub fn open(path: &str, flags: OpenFlags) -> isize { sys_open(path, flags.bits) }
 pub fn close(fd: usize) -> isize { sys_close(fd) }
+pub fn rename(old_path: &str, new_path: &str) -> isize { sys_renameat(old_path, new_path) }
 pub fn pipe(pipe_fd: &mut [usize]) -> isize { sys_pipe(pipe_fd) }
 pub fn read(fd: usize, buf: &mut [u8]) -> isize { sys_read(fd, buf) }
 pub fn write(fd: usize, buf: &[u8]) -> isize { sys_write(fd, buf) }
+pub fn fstat(fd: usize, st: &mut Stat) -> isize { sys_fstat(fd, st) }
+pub fn ftruncate(fd: usize, length: usize) -> isize { sys_ftruncate(fd, length) }
+pub fn statfs(st: &mut StatFs) -> isize { sys_statfs(st) }
+pub fn fsetxattr(fd: usize, name: &str, value: &[u8]) -> isize { sys_fsetxattr(fd, name, value) }
+pub fn fgetxattr(fd: usize, name: &str, value: &mut [u8]) -> isize { sys_fgetxattr(fd, name, value) }
+pub fn sync() -> isize { sys_sync() }
+pub fn fsync(fd: usize) -> isize { sys_fsync(fd) }
 pub fn exit(exit_code: i32) -> ! { sys_exit(exit_code); }
 pub fn yield_() -> isize { sys_yield() }
 pub fn get_time() -> isize { sys_get_time() }
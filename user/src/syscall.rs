@@ -1,9 +1,19 @@
+use super::{Stat, StatFs};
+
+const SYSCALL_FSETXATTR: usize = 7;
+const SYSCALL_FGETXATTR: usize = 10;
 const SYSCALL_DUP: usize = 24;
+const SYSCALL_RENAMEAT: usize = 38;
+const SYSCALL_STATFS: usize = 43;
+const SYSCALL_FTRUNCATE: usize = 46;
 const SYSCALL_OPEN: usize = 56;
 const SYSCALL_CLOSE: usize = 57;
 const SYSCALL_PIPE: usize = 59;
 const SYSCALL_READ: usize = 63;
 const SYSCALL_WRITE: usize = 64;
+const SYSCALL_FSTAT: usize = 80;
+const SYSCALL_SYNC: usize = 81;
+const SYSCALL_FSYNC: usize = 82;
 const SYSCALL_EXIT: usize = 93;
 const SYSCALL_YIELD: usize = 124;
 const SYSCALL_GET_TIME: usize = 169;
@@ -25,10 +35,27 @@ fn syscall(id: usize, args: [usize; 3]) -> isize {
     ret
 }
 
+fn syscall4(id: usize, args: [usize; 4]) -> isize {
+    let mut ret: isize;
+    unsafe {
+        llvm_asm!("ecall"
+            : "={x10}" (ret)
+            : "{x10}" (args[0]), "{x11}" (args[1]), "{x12}" (args[2]), "{x13}" (args[3]), "{x17}" (id)
+            : "memory"
+            : "volatile"
+        );
+    }
+    ret
+}
+
 pub fn sys_dup(fd: usize) -> isize {
     syscall(SYSCALL_DUP, [fd, 0, 0])
 }
 
+pub fn sys_renameat(old_path: &str, new_path: &str) -> isize {
+    syscall(SYSCALL_RENAMEAT, [old_path.as_ptr() as usize, new_path.as_ptr() as usize, 0])
+}
+
 pub fn sys_open(path: &str, flags: u32) -> isize {
     syscall(SYSCALL_OPEN, [path.as_ptr() as usize, flags as usize, 0])
 }
@@ -49,6 +76,34 @@ pub fn sys_write(fd: usize, buffer: &[u8]) -> isize {
     syscall(SYSCALL_WRITE, [fd, buffer.as_ptr() as usize, buffer.len()])
 }
 
+pub fn sys_fstat(fd: usize, st: &mut Stat) -> isize {
+    syscall(SYSCALL_FSTAT, [fd, st as *mut _ as usize, 0])
+}
+
+pub fn sys_statfs(st: &mut StatFs) -> isize {
+    syscall(SYSCALL_STATFS, [st as *mut _ as usize, 0, 0])
+}
+
+pub fn sys_ftruncate(fd: usize, length: usize) -> isize {
+    syscall(SYSCALL_FTRUNCATE, [fd, length, 0])
+}
+
+pub fn sys_fsetxattr(fd: usize, name: &str, value: &[u8]) -> isize {
+    syscall4(SYSCALL_FSETXATTR, [fd, name.as_ptr() as usize, value.as_ptr() as usize, value.len()])
+}
+
+pub fn sys_fgetxattr(fd: usize, name: &str, value: &mut [u8]) -> isize {
+    syscall4(SYSCALL_FGETXATTR, [fd, name.as_ptr() as usize, value.as_mut_ptr() as usize, value.len()])
+}
+
+pub fn sys_sync() -> isize {
+    syscall(SYSCALL_SYNC, [0, 0, 0])
+}
+
+pub fn sys_fsync(fd: usize) -> isize {
+    syscall(SYSCALL_FSYNC, [fd, 0, 0])
+}
+
 pub fn sys_exit(exit_code: i32) -> ! {
     syscall(SYSCALL_EXIT, [exit_code as usize, 0, 0]);
     panic!("sys_exit never returns!");